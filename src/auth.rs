@@ -0,0 +1,29 @@
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_SIZE: usize = 32;
+
+// A random challenge, sent to a client so it can prove it knows the secret
+pub fn new_nonce() -> Vec<u8> {
+    let mut nonce = vec![0; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+
+    return nonce;
+}
+
+// Checks that the client answered the challenge with the HMAC-SHA256 of the nonce, keyed with
+// the secret
+pub fn verify(secret: &[u8], nonce: &[u8], answer: &[u8]) -> bool {
+    let mut mac = match HmacSha256::new_from_slice(secret) {
+        Err(_) => return false,
+        Ok(m) => m,
+    };
+    mac.update(nonce);
+
+    // Compares in constant time, so the answer can't be guessed byte by byte
+    return mac.verify_slice(answer).is_ok();
+}
@@ -1,35 +1,169 @@
 use chrono::prelude::*;
+use std::fmt;
+use std::io;
 use std::io::Write;
-use std::net::TcpStream;
-use std::sync::mpsc::channel;
+use std::mem;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
+use mio::net::TcpStream;
+use mio::{Token, Waker};
+
+use auth;
 use common::*;
+use handshake::Handshake;
 use parse::*;
 use read::*;
 use utils::*;
 
+// A response body or error, along with the ID of the request it answers
+pub type Response = (u32, Result<Vec<u8>, MyError>);
+
+// Hands responses to the event loop, along with the token of the client they are meant for
+#[derive(Clone)]
+pub struct ResponseQueue {
+    token: Token,
+    sender: Sender<(Token, Response)>,
+
+    // Wakes the event loop up so the response gets written
+    waker: Arc<Waker>,
+}
+
+impl ResponseQueue {
+    pub fn new(
+        token: Token,
+        sender: Sender<(Token, Response)>,
+        waker: Arc<Waker>,
+    ) -> ResponseQueue {
+        return ResponseQueue {
+            token,
+            sender,
+            waker,
+        };
+    }
+
+    fn send(&self, response: Response) {
+        // The event loop only stops when the server does, in which case there's no one to tell
+        if self.sender.send((self.token, response)).is_ok() {
+            if let Err(e) = self.waker.wake() {
+                error!("Error waking up the event loop: {}", e);
+            }
+        }
+    }
+}
+
+enum Destination<T> {
+    // The client that sent the command
+    Client {
+        request_id: u32,
+        queue: ResponseQueue,
+
+        // Turns the answer into a response body
+        encode: fn(T) -> Vec<u8>,
+    },
+    // The server itself, e.g. to combine the answers of every channel
+    Local(Sender<Result<T, MyError>>),
+}
+
+// Sends the answer to a command, or the reason it could not be handled, back to the client that
+// sent it
+pub struct ResponseSender<T> {
+    destination: Destination<T>,
+}
+
+impl<T> ResponseSender<T> {
+    fn new(request_id: u32, queue: ResponseQueue, encode: fn(T) -> Vec<u8>) -> ResponseSender<T> {
+        return ResponseSender {
+            destination: Destination::Client {
+                request_id,
+                queue,
+                encode,
+            },
+        };
+    }
+
+    pub fn local(sender: Sender<Result<T, MyError>>) -> ResponseSender<T> {
+        return ResponseSender {
+            destination: Destination::Local(sender),
+        };
+    }
+
+    pub fn send(self, result: Result<T, MyError>) {
+        match self.destination {
+            Destination::Client {
+                request_id,
+                queue,
+                encode,
+            } => queue.send((request_id, result.map(encode))),
+            Destination::Local(sender) => {
+                // Whoever was waiting for the answer is gone
+                let _ = sender.send(result);
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for ResponseSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.destination {
+            Destination::Client { request_id, .. } => {
+                write!(f, "ResponseSender {{ request_id: {} }}", request_id)
+            }
+            Destination::Local(_) => write!(f, "ResponseSender {{ local }}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Connect {
+    pub channel_name: String,
+
+    // Ok once the channel is ready to be used
+    pub response_sender: ResponseSender<()>,
+}
+
 #[derive(Debug)]
 pub struct GetPoints {
     pub channel_name: String,
     pub user_id: String,
-    pub response_sender: Sender<u64>,
+    pub response_sender: ResponseSender<u64>,
+}
+
+#[derive(Debug)]
+pub struct GetPointsBulk {
+    pub channel_name: String,
+    pub user_ids: Vec<String>,
+
+    // Points and rank of each user
+    pub response_sender: ResponseSender<Vec<(u64, u64)>>,
 }
 
 #[derive(Debug)]
 pub struct BulkEdit {
     pub channel_name: String,
 
-    pub user_ids: Vec<String>,
+    // User IDs, and how many points to edit (positive for add, negative for remove)
+    pub edits: Vec<(String, i64)>,
+
+    // Told how the edits went once they are made, if the client asked for it
+    pub response_sender: Option<ResponseSender<BulkEditSummary>>,
+}
 
-    // How many points to edit (positive for add, negative for remove)
-    pub points: i32,
+#[derive(Debug, Default)]
+pub struct BulkEditSummary {
+    // Users whose points were edited by the whole amount
+    pub applied: u32,
+    // Edits with no amount or no user ID
+    pub skipped: u32,
+    // Users whose points hit 0 or the maximum before the whole amount was edited
+    pub clamped: u32,
 }
 
 #[derive(Debug)]
 pub enum Operation {
     Add,
     Remove,
+    Set,
 }
 
 #[derive(Debug)]
@@ -44,8 +178,8 @@ pub struct Edit {
     // Force set
     pub force: bool,
 
-    // New value total for user
-    pub response_sender: Sender<(bool, u64)>,
+    // Whether the edit was made, and the users points before and after
+    pub response_sender: ResponseSender<(bool, u64, u64)>,
 }
 
 #[derive(Debug)]
@@ -54,217 +188,1211 @@ pub struct Rank {
     pub user_id: String,
 
     // Rank of user
-    pub response_sender: Sender<u64>,
+    pub response_sender: ResponseSender<u64>,
+}
+
+#[derive(Debug)]
+pub struct Transfer {
+    pub channel_name: String,
+    pub from_user_id: String,
+    pub to_user_id: String,
+
+    pub amount: u64,
+
+    // Whether the transfer was made, and the points of both users afterwards
+    pub response_sender: ResponseSender<(bool, u64, u64)>,
+}
+
+#[derive(Debug)]
+pub struct Delete {
+    pub channel_name: String,
+    pub user_ids: Vec<String>,
+
+    // Delete the users from every channel, instead of only the named one
+    pub all_channels: bool,
+
+    // The users that were deleted
+    pub response_sender: ResponseSender<ChannelResults<DeletedUser>>,
+}
+
+#[derive(Debug)]
+pub struct DeletedUser {
+    pub channel_name: String,
+    pub user_id: String,
+
+    // Points the user had when they were deleted
+    pub points: u64,
+}
+
+#[derive(Debug)]
+pub struct Merge {
+    pub channel_name: String,
+    pub from_user_id: String,
+    pub to_user_id: String,
+
+    // Merge the users in every channel, instead of only the named one
+    pub all_channels: bool,
+
+    // The channels the users were merged in
+    pub response_sender: ResponseSender<ChannelResults<MergedUser>>,
+}
+
+#[derive(Debug)]
+pub struct MergedUser {
+    pub channel_name: String,
+
+    // Points moved from one user to the other
+    pub points: u64,
+    // Points of the user that was merged into
+    pub new_points: u64,
+}
+
+// Answers of a command that runs in one or every channel, along with the channels it could not
+// run in
+pub struct ChannelResults<T> {
+    pub results: Vec<T>,
+    pub failures: Vec<ChannelFailure>,
+}
+
+impl<T> ChannelResults<T> {
+    pub fn new(results: Vec<T>) -> ChannelResults<T> {
+        return ChannelResults {
+            results,
+            failures: Vec::new(),
+        };
+    }
+}
+
+pub struct ChannelFailure {
+    pub channel_name: String,
+    pub error: MyError,
+}
+
+#[derive(Debug)]
+pub struct Reset {
+    pub channel_name: String,
+
+    // File name of the archived copy of the channel
+    pub response_sender: ResponseSender<String>,
+}
+
+#[derive(Debug)]
+pub struct Leaderboard {
+    pub channel_name: String,
+
+    // Zero-based position of the first user to get
+    pub offset: usize,
+    // Number of users to get
+    pub limit: usize,
+
+    pub response_sender: ResponseSender<Vec<LeaderboardEntry>>,
+}
+
+#[derive(Debug)]
+pub struct Around {
+    pub channel_name: String,
+    pub user_id: String,
+
+    // Number of users to get above and below the user
+    pub count: usize,
+
+    pub response_sender: ResponseSender<Vec<LeaderboardEntry>>,
+}
+
+#[derive(Debug)]
+pub struct CloseSeason {
+    pub channel_name: String,
+    pub season_id: String,
+
+    // Remove every user from the channel once the season is closed
+    pub reset: bool,
+
+    // Number of users in the season
+    pub response_sender: ResponseSender<u64>,
+}
+
+#[derive(Debug)]
+pub struct SeasonLeaderboard {
+    pub channel_name: String,
+    pub season_id: String,
+
+    // Number of users to get
+    pub limit: usize,
+
+    pub response_sender: ResponseSender<Vec<LeaderboardEntry>>,
+}
+
+#[derive(Debug)]
+pub struct SeasonRank {
+    pub channel_name: String,
+    pub season_id: String,
+    pub user_id: String,
+
+    // None if the user was not in the season
+    pub response_sender: ResponseSender<Option<LeaderboardEntry>>,
+}
+
+#[derive(Debug)]
+pub struct LeaderboardEntry {
+    pub user_id: String,
+    pub points: u64,
+    pub rank: u64,
 }
 
 #[derive(Debug)]
 pub enum Command {
+    // Sent when a client connects to a channel, creates the channel if it doesn't exist yet
+    Connect(Connect),
     GetPoints(GetPoints),
-    SavePoints,
+    GetPointsBulk(GetPointsBulk),
+    // Save points to disk. Acknowledged once the save is done
+    SavePoints(Sender<()>),
+    // Save points to disk and stop. Acknowledged once the save is done
     Quit(Sender<()>),
     BulkEdit(BulkEdit),
     Edit(Edit),
     Rank(Rank),
+    Leaderboard(Leaderboard),
+    Around(Around),
+    Transfer(Transfer),
+    Delete(Delete),
+    Merge(Merge),
+    Reset(Reset),
+    CloseSeason(CloseSeason),
+    SeasonLeaderboard(SeasonLeaderboard),
+    SeasonRank(SeasonRank),
 }
 
+impl Command {
+    // Name of the channel the command should be forwarded to.
+    // Returns None for commands that are meant for every channel
+    pub fn channel_name(&self) -> Option<&str> {
+        use self::Command::*;
+
+        match self {
+            Connect(c) => Some(&c.channel_name),
+            GetPoints(c) => Some(&c.channel_name),
+            GetPointsBulk(c) => Some(&c.channel_name),
+            BulkEdit(c) => Some(&c.channel_name),
+            Edit(c) => Some(&c.channel_name),
+            Rank(c) => Some(&c.channel_name),
+            Leaderboard(c) => Some(&c.channel_name),
+            Around(c) => Some(&c.channel_name),
+            Transfer(c) => Some(&c.channel_name),
+            Delete(c) => Some(&c.channel_name),
+            Merge(c) => Some(&c.channel_name),
+            Reset(c) => Some(&c.channel_name),
+            CloseSeason(c) => Some(&c.channel_name),
+            SeasonLeaderboard(c) => Some(&c.channel_name),
+            SeasonRank(c) => Some(&c.channel_name),
+            SavePoints(_) | Quit(_) => None,
+        }
+    }
+
+    // Answers the command with an error instead of handling it
+    pub fn reject(self, error: MyError) {
+        use self::Command::*;
+
+        match self {
+            Connect(c) => c.response_sender.send(Err(error)),
+            GetPoints(c) => c.response_sender.send(Err(error)),
+            GetPointsBulk(c) => c.response_sender.send(Err(error)),
+            Edit(c) => c.response_sender.send(Err(error)),
+            Rank(c) => c.response_sender.send(Err(error)),
+            Leaderboard(c) => c.response_sender.send(Err(error)),
+            Around(c) => c.response_sender.send(Err(error)),
+            Transfer(c) => c.response_sender.send(Err(error)),
+            Delete(c) => c.response_sender.send(Err(error)),
+            Merge(c) => c.response_sender.send(Err(error)),
+            Reset(c) => c.response_sender.send(Err(error)),
+            CloseSeason(c) => c.response_sender.send(Err(error)),
+            SeasonLeaderboard(c) => c.response_sender.send(Err(error)),
+            SeasonRank(c) => c.response_sender.send(Err(error)),
+            BulkEdit(c) => {
+                if let Some(response_sender) = c.response_sender {
+                    response_sender.send(Err(error));
+                }
+            }
+            SavePoints(_) | Quit(_) => {}
+        }
+    }
+}
+
+enum State {
+    // Waiting for the COMMAND_CONNECT command
+    Handshake,
+    // Waiting for the COMMAND_AUTH command, with the nonce the client was challenged with
+    Authenticating(Handshake, Vec<u8>),
+    // Waiting for the channel to be ready, with the body of the response to send once it is.
+    // Commands are not read until then
+    Connecting(Handshake, Vec<u8>),
+    // Connected with the accepted handshake. The channel name is the channel commands are for,
+    // which the client can change with COMMAND_SELECT_CHANNEL
+    Connected(Handshake),
+    // Nothing more is read, the client is disconnected once every response has been written
+    Closing,
+}
+
+// A connection to a client, driven by the event loop
 pub struct Client {
     stream: TcpStream,
-    // point_channel_map: ChannelPointMap,
-    channel_name: String,
+    state: State,
     request_sender: Sender<Command>,
+    response_queue: ResponseQueue,
+
+    // Largest body size the client may send
+    max_body_size: u32,
+    // The client must authenticate with this secret, if there is one
+    secret: Option<Arc<Vec<u8>>>,
+    // Most entries a leaderboard command may return
+    max_leaderboard_size: u32,
+
+    // Bytes read from the client that are not part of a handled command yet
+    read_buffer: Vec<u8>,
+    // Responses that could not be written yet
+    write_buffer: Vec<u8>,
+    // Bytes left of a body that is too large to be handled
+    skip_remaining: u64,
+    // Reading stopped because the buffers are full. Resumed once they have room again
+    read_paused: bool,
+
+    // Commands that have been passed on, but not answered yet
+    pending_responses: usize,
 }
 
 impl Client {
-    pub fn new(mut stream: TcpStream, sender: Sender<Command>) -> Result<Client, MyError> {
-        let (command, body_size) = read_header(&mut stream)?;
-        if command != COMMAND_CONNECT {
-            return Err(MyError::WrongCommand(WrongCommand::new(
-                command,
-                COMMAND_CONNECT,
-            )));
-        }
+    pub fn new(
+        stream: TcpStream,
+        request_sender: Sender<Command>,
+        response_queue: ResponseQueue,
+        max_body_size: u32,
+        secret: Option<Arc<Vec<u8>>>,
+        max_leaderboard_size: u32,
+    ) -> Client {
+        return Client {
+            stream,
+            state: State::Handshake,
+            request_sender,
+            response_queue,
+            max_body_size,
+            secret,
+            max_leaderboard_size,
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+            skip_remaining: 0,
+            read_paused: false,
+            pending_responses: 0,
+        };
+    }
 
-        let body_buf = read_body(&mut stream, body_size as usize)?;
-        let channel_name = String::from_utf8(body_buf).map_err(|e| MyError::ParseError(e))?;
+    pub fn stream(&mut self) -> &mut TcpStream {
+        return &mut self.stream;
+    }
 
-        return Ok(Client {
-            stream: stream,
-            channel_name: channel_name,
-            request_sender: sender,
-        });
+    // True once the client can be dropped
+    pub fn is_done(&self) -> bool {
+        match self.state {
+            State::Closing => return self.write_buffer.is_empty() && self.pending_responses == 0,
+            _ => return false,
+        }
     }
 
-    pub fn run(&mut self) {
-        println!("Running client {:?}", self.stream);
+    // Reads and handles every command that has arrived.
+    // Errors that leave the stream in sync are sent to the client, any other error is returned
+    pub fn on_readable(&mut self) -> Result<(), MyError> {
         loop {
-            match self.handle_command() {
+            if let State::Closing = self.state {
+                return Ok(());
+            }
+
+            // Commands are not read faster than they can be handled, or than the client reads
+            // the responses
+            if self.is_backlogged() {
+                self.read_paused = true;
+                return Ok(());
+            }
+            self.read_paused = false;
+
+            let limit = self.max_buffered();
+            let read_state = read_available(&mut self.stream, &mut self.read_buffer, limit)?;
+            self.handle_commands()?;
+
+            match read_state {
+                ReadState::Drained => return Ok(()),
+                ReadState::Full => continue,
+                ReadState::Closed => {
+                    debug!("Client {:?} disconnected", self.stream);
+                    self.state = State::Closing;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Writes as many of the waiting responses as the client will take, and goes on reading if
+    // reading was paused until there was room
+    pub fn on_writable(&mut self) -> Result<(), MyError> {
+        self.write_available()?;
+
+        if self.read_paused && !self.is_backlogged() {
+            return self.on_readable();
+        }
+
+        return Ok(());
+    }
+
+    // Largest body the client may send right now
+    fn body_limit(&self) -> u32 {
+        match self.state {
+            State::Connected(_) => return self.max_body_size,
+            _ => return MAX_HANDSHAKE_BODY_SIZE.min(self.max_body_size),
+        }
+    }
+
+    // Input and output are each held up to the size of the largest command
+    fn max_buffered(&self) -> usize {
+        return self.body_limit() as usize + HEADER_SIZE;
+    }
+
+    fn is_backlogged(&self) -> bool {
+        let max_buffered = self.max_buffered();
+
+        return self.read_buffer.len() >= max_buffered || self.write_buffer.len() >= max_buffered;
+    }
+
+    fn write_available(&mut self) -> Result<(), MyError> {
+        while !self.write_buffer.is_empty() {
+            match self.stream.write(&self.write_buffer) {
+                // Retry
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+
+                // The rest is written once the client is writable again
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+
+                Err(e) => return Err(MyError::IoError(e)),
+                Ok(0) => return Err(MyError::IoError(io::ErrorKind::WriteZero.into())),
+                Ok(n) => {
+                    self.write_buffer.drain(..n);
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Called by the event loop with the answer to a command that was passed on
+    pub fn on_response(&mut self, response: Response) -> Result<(), MyError> {
+        self.pending_responses = self.pending_responses.saturating_sub(1);
+
+        let (request_id, result) = response;
+
+        match mem::replace(&mut self.state, State::Closing) {
+            State::Connecting(handshake, reply) => match result {
                 Err(e) => {
-                    // Something that went wrong, went wrong.
-                    // If we can recover from the error, or tell the client that something went
-                    // wrong, we should probably do that.
-                    // For now, disconnecting and letting the client reconnect is probably the best
-                    // thing
-                    println!("An error occured in handle_command: {}", e);
-                    break;
+                    // Tell the client why the connection failed before disconnecting
+                    info!(
+                        "Error connecting {} to {}: {}",
+                        handshake.client_name, handshake.channel_name, e
+                    );
+                    self.respond(request_id, Err(e));
                 }
-                Ok(_) => {}
+                Ok(_) => {
+                    info!(
+                        "{} connected to {} with protocol version {}",
+                        handshake.client_name, handshake.channel_name, handshake.protocol_version
+                    );
+                    self.respond(request_id, Ok(reply));
+                    self.state = State::Connected(handshake);
+
+                    // Commands sent right after connecting have been waiting for this
+                    self.handle_commands()?;
+                }
+            },
+            state => {
+                self.state = state;
+                self.respond(request_id, result);
             }
         }
+
+        return self.on_writable();
     }
 
-    // Blocks and reads + handles the next incoming command
-    // TODO: We might want a way to stop at the "waiting for header size" stage in case of quitting
-    fn handle_command(&mut self) -> Result<(), MyError> {
-        let (command, body_size) = read_header(&mut self.stream)?;
-        let body = read_body(&mut self.stream, body_size as usize)?;
+    // Handles every complete command in the read buffer
+    fn handle_commands(&mut self) -> Result<(), MyError> {
+        let mut offset = 0;
 
+        loop {
+            if self.skip_remaining > 0 {
+                let available = (self.read_buffer.len() - offset) as u64;
+                let skipped = self.skip_remaining.min(available);
+                offset += skipped as usize;
+                self.skip_remaining -= skipped;
+                if self.skip_remaining > 0 {
+                    break;
+                }
+            }
+
+            match self.state {
+                State::Handshake | State::Authenticating(..) | State::Connected(_) => {}
+                // Wait for the channel before handling anything else
+                State::Connecting(..) | State::Closing => break,
+            }
+
+            let header = match parse_header(&self.read_buffer[offset..]) {
+                None => break,
+                Some(h) => h,
+            };
+
+            if header.body_size > self.body_limit() {
+                // Skip the body so the next command can be read
+                offset += HEADER_SIZE;
+                self.skip_remaining = u64::from(header.body_size);
+                self.fail(&header, MyError::BodyTooLarge(header.body_size));
+                continue;
+            }
+
+            let command_size = HEADER_SIZE + header.body_size as usize;
+            if self.read_buffer.len() - offset < command_size {
+                break;
+            }
+
+            let body = self.read_buffer[offset + HEADER_SIZE..offset + command_size].to_vec();
+            offset += command_size;
+
+            self.handle_command(&header, body);
+        }
+
+        self.read_buffer.drain(..offset);
+
+        return self.write_available();
+    }
+
+    // Passes the command on without waiting for the answer
+    fn handle_command(&mut self, header: &Header, body: Vec<u8>) {
         let start = Utc::now();
-        println!("Handle command {}...", command);
-        if let Some(response) = match command {
-            COMMAND_GET => self.handle_get_points(body.to_vec())?,
-            COMMAND_BULK_EDIT => self.handle_bulk_edit(body.to_vec())?,
-            COMMAND_ADD => self.handle_add(body.to_vec())?,
-            COMMAND_REMOVE => self.handle_remove(body.to_vec())?,
-            COMMAND_RANK => self.handle_rank(body.to_vec())?,
+        debug!(
+            "Handle command {} with request ID {}...",
+            header.command, header.request_id
+        );
+        let request_id = header.request_id;
+        let channel_name = match self.state {
+            State::Connected(ref handshake) => handshake.channel_name.clone(),
             _ => {
-                println!("Unknown command {}", command);
-                None
+                if let Err(e) = self.handle_handshake(header, body) {
+                    self.fail(header, e);
+                }
+                return;
+            }
+        };
+        let result = match header.command {
+            COMMAND_GET => self.handle_get_points(request_id, channel_name, body),
+            COMMAND_BULK_EDIT => self.handle_bulk_edit(request_id, channel_name, body),
+            COMMAND_BULK_EDIT_AMOUNTS => {
+                self.handle_bulk_edit_amounts(request_id, channel_name, body)
+            }
+            COMMAND_ADD => self.handle_add(request_id, channel_name, body),
+            COMMAND_REMOVE => self.handle_remove(request_id, channel_name, body),
+            COMMAND_RANK => self.handle_rank(request_id, channel_name, body),
+            COMMAND_SELECT_CHANNEL => self.handle_select_channel(request_id, body),
+            COMMAND_TOP => self.handle_top(request_id, channel_name, body),
+            COMMAND_RANGE => self.handle_range(request_id, channel_name, body),
+            COMMAND_AROUND => self.handle_around(request_id, channel_name, body),
+            COMMAND_TRANSFER => self.handle_transfer(request_id, channel_name, body),
+            COMMAND_SET => self.handle_set(request_id, channel_name, body),
+            COMMAND_DELETE => self.handle_delete(request_id, channel_name, body),
+            COMMAND_MERGE => self.handle_merge(request_id, channel_name, body),
+            COMMAND_RESET => self.handle_reset(request_id, channel_name),
+            COMMAND_CLOSE_SEASON => self.handle_close_season(request_id, channel_name, body),
+            COMMAND_SEASON_TOP => self.handle_season_top(request_id, channel_name, body),
+            COMMAND_SEASON_RANK => self.handle_season_rank(request_id, channel_name, body),
+            COMMAND_GET_BULK => self.handle_get_points_bulk(request_id, channel_name, body),
+            _ => {
+                warn!("Unknown command {}", header.command);
+                Err(MyError::UnknownCommand(header.command))
             }
-        } {
-            self.respond(response)?;
+        };
+        if let Err(e) = result {
+            self.respond(request_id, Err(e));
         }
         let end = Utc::now();
-        println!("Handling command {} took {}", command, end - start);
+        debug!("Handling command {} took {}", header.command, end - start);
+    }
 
-        return Ok(());
+    // Answers a command with an error. Errors before the client is connected to a channel end
+    // the connection
+    fn fail(&mut self, header: &Header, error: MyError) {
+        match self.state {
+            State::Connected(_) => {}
+            _ => {
+                info!("Error connecting to client {:?}: {}", self.stream, error);
+                self.state = State::Closing;
+            }
+        }
+
+        self.respond(header.request_id, Err(error));
     }
 
-    fn respond(&mut self, response: Vec<u8>) -> Result<(), MyError> {
-        self.stream
-            .write(&response)
-            .map_err(|e| MyError::IoError(e))?;
+    // Adds a response to the write buffer
+    fn respond(&mut self, request_id: u32, result: Result<Vec<u8>, MyError>) {
+        match result {
+            Err(e) => {
+                debug!("Responding to request {} with error: {}", request_id, e);
 
-        return Ok(());
+                // The body is the error code followed by a description of the error
+                let mut body = vec![e.code()];
+                body.extend_from_slice(e.to_string().as_bytes());
+
+                self.write_response(RESPONSE_ERR, request_id, &body);
+            }
+            Ok(body) => self.write_response(RESPONSE_OK, request_id, &body),
+        }
+    }
+
+    fn write_response(&mut self, status: u8, request_id: u32, body: &[u8]) {
+        self.write_buffer.push(status);
+        self.write_buffer.extend_from_slice(&u32_to_buf(request_id));
+        self.write_buffer
+            .extend_from_slice(&u32_to_buf(body.len() as u32));
+        self.write_buffer.extend_from_slice(body);
     }
 
-    fn handle_get_points(&mut self, buffer: Vec<u8>) -> Result<Option<Vec<u8>>, MyError> {
-        let user_id = parse_user_id(buffer.to_vec())?;
+    fn response_sender<T>(&self, request_id: u32, encode: fn(T) -> Vec<u8>) -> ResponseSender<T> {
+        return ResponseSender::new(request_id, self.response_queue.clone(), encode);
+    }
 
-        let (sender, receiver) = channel();
+    fn send(&mut self, command: Command) -> Result<(), MyError> {
+        // Bulk edits are answered right away, unless the client asked to be told how they went
+        let answered_later = match command {
+            Command::BulkEdit(ref c) => c.response_sender.is_some(),
+            _ => true,
+        };
 
         self.request_sender
-            .send(Command::GetPoints(GetPoints {
-                channel_name: self.channel_name.clone(),
-                user_id: user_id,
-                response_sender: sender,
-            }))
+            .send(command)
             .map_err(|e| MyError::SendError(e.to_string()))?;
 
-        let points = receiver.recv().map_err(|e| MyError::RecvError(e))?;
-        return Ok(Some(u64_to_buf(points).to_vec()));
+        if answered_later {
+            self.pending_responses += 1;
+        }
+
+        return Ok(());
     }
 
-    fn handle_bulk_edit(&mut self, buffer: Vec<u8>) -> Result<Option<Vec<u8>>, MyError> {
+    // Handles the commands that come before the client is connected to a channel
+    fn handle_handshake(&mut self, header: &Header, body: Vec<u8>) -> Result<(), MyError> {
+        match mem::replace(&mut self.state, State::Closing) {
+            State::Handshake => return self.handle_connect(header, body),
+            State::Authenticating(handshake, nonce) => {
+                return self.handle_auth(header, body, handshake, &nonce)
+            }
+            state => {
+                self.state = state;
+                return Ok(());
+            }
+        }
+    }
+
+    fn handle_connect(&mut self, header: &Header, body: Vec<u8>) -> Result<(), MyError> {
+        if header.command != COMMAND_CONNECT {
+            return Err(MyError::WrongCommand(WrongCommand::new(
+                header.command,
+                COMMAND_CONNECT,
+            )));
+        }
+
+        let handshake = Handshake::parse(body)?.negotiate()?;
+
+        if self.secret.is_none() {
+            let reply = handshake.encode_reply(&[]);
+            return self.connect(header.request_id, handshake, reply);
+        }
+
+        // The channel is not touched until the client has proven it knows the secret
+        let nonce = auth::new_nonce();
+        self.respond(header.request_id, Ok(handshake.encode_reply(&nonce)));
+        self.state = State::Authenticating(handshake, nonce);
+
+        return Ok(());
+    }
+
+    fn handle_auth(
+        &mut self,
+        header: &Header,
+        body: Vec<u8>,
+        handshake: Handshake,
+        nonce: &[u8],
+    ) -> Result<(), MyError> {
+        if header.command != COMMAND_AUTH {
+            return Err(MyError::WrongCommand(WrongCommand::new(
+                header.command,
+                COMMAND_AUTH,
+            )));
+        }
+
+        let authenticated = match self.secret {
+            None => true,
+            Some(ref secret) => auth::verify(secret, nonce, &body),
+        };
+        if !authenticated {
+            warn!(
+                "{} failed to authenticate from {:?}",
+                handshake.client_name,
+                self.stream.peer_addr()
+            );
+            return Err(MyError::AuthenticationFailed);
+        }
+
+        return self.connect(header.request_id, handshake, Vec::new());
+    }
+
+    // Connects the client to the channel it asked for, and sends the reply once it's ready
+    fn connect(
+        &mut self,
+        request_id: u32,
+        handshake: Handshake,
+        reply: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let response_sender = self.response_sender(request_id, encode_empty);
+        self.send(Command::Connect(Connect {
+            channel_name: handshake.channel_name.clone(),
+            response_sender,
+        }))?;
+
+        self.state = State::Connecting(handshake, reply);
+
+        return Ok(());
+    }
+
+    fn handle_select_channel(&mut self, request_id: u32, buffer: Vec<u8>) -> Result<(), MyError> {
+        let channel_name = parse_channel_name(buffer)?;
+
+        // Points creates the channel if needed before it handles the commands that follow
+        let response_sender = self.response_sender(request_id, encode_empty);
+        self.send(Command::Connect(Connect {
+            channel_name: channel_name.clone(),
+            response_sender,
+        }))?;
+
+        debug!("Client {:?} selected channel {}", self.stream, channel_name);
+        if let State::Connected(ref mut handshake) = self.state {
+            handshake.channel_name = channel_name;
+        }
+
+        return Ok(());
+    }
+
+    fn handle_get_points(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let user_id = parse_user_id(buffer)?;
+
+        let response_sender = self.response_sender(request_id, encode_u64);
+        return self.send(Command::GetPoints(GetPoints {
+            channel_name,
+            user_id,
+            response_sender,
+        }));
+    }
+
+    fn handle_get_points_bulk(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let user_ids = parse_user_id_bulk(buffer)?;
+
+        let response_sender = self.response_sender(request_id, encode_points_bulk);
+        return self.send(Command::GetPointsBulk(GetPointsBulk {
+            channel_name,
+            user_ids,
+            response_sender,
+        }));
+    }
+
+    fn handle_bulk_edit(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        if buffer.len() < 4 {
+            return Err(MyError::BufferError);
+        }
+
         // Read points from 4 first bytes
         let points = buf_to_i32_unsafe(&buffer[0..4]);
 
         // Read user ID into a string from remaining bytes
         let user_ids = parse_user_id_bulk(buffer[4..].to_vec())?;
 
-        self.request_sender
-            .send(Command::BulkEdit(BulkEdit {
-                channel_name: self.channel_name.clone(),
-                user_ids: user_ids,
-                points: points,
-            }))
-            .unwrap();
+        let edits = user_ids
+            .into_iter()
+            .map(|user_id| (user_id, i64::from(points)))
+            .collect();
+
+        self.send(Command::BulkEdit(BulkEdit {
+            channel_name,
+            edits,
+            response_sender: None,
+        }))?;
 
-        return Ok(None);
+        // Bulk edits are not waited for, the response only says the edit was accepted
+        self.respond(request_id, Ok(Vec::new()));
+
+        return Ok(());
     }
 
-    fn handle_add(&mut self, buffer: Vec<u8>) -> Result<Option<Vec<u8>>, MyError> {
-        // Read points from 8 first bytes
-        let points = buf_to_u64(&buffer[0..8])?;
+    fn handle_bulk_edit_amounts(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        if buffer.is_empty() {
+            return Err(MyError::BufferError);
+        }
 
-        // Read user ID into a string from remaining bytes
-        let user_id = parse_user_id(buffer[8..].to_vec())?;
+        // Read the flag from the first byte
+        let ack = buffer[0] == BULK_EDIT_ACK;
 
-        let (sender, receiver) = channel();
+        let edits = parse_amount_bulk(buffer[1..].to_vec())?;
 
-        self.request_sender
-            .send(Command::Edit(Edit {
-                channel_name: self.channel_name.clone(),
-                user_id: user_id,
-                operation: Operation::Add,
-                value: points,
-                force: false,
-                response_sender: sender,
-            }))
-            .unwrap();
+        let response_sender = if ack {
+            Some(self.response_sender(request_id, encode_bulk_edit_summary))
+        } else {
+            None
+        };
 
-        let mut response = Vec::new();
+        self.send(Command::BulkEdit(BulkEdit {
+            channel_name,
+            edits,
+            response_sender,
+        }))?;
 
-        let (result_bool, user_points) = receiver.recv().unwrap();
-        let result = if result_bool { RESULT_OK } else { RESULT_ERR };
+        if !ack {
+            self.respond(request_id, Ok(Vec::new()));
+        }
+
+        return Ok(());
+    }
+
+    fn handle_add(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        if buffer.len() < 8 {
+            return Err(MyError::BufferError);
+        }
 
-        let user_points_buf = u64_to_buf(user_points);
+        // Read points from 8 first bytes
+        let points = buf_to_u64(&buffer[0..8])?;
 
-        response.push(result);
-        response.append(&mut user_points_buf.to_vec());
+        // Read user ID into a string from remaining bytes
+        let user_id = parse_user_id(buffer[8..].to_vec())?;
 
-        return Ok(Some(response));
+        let response_sender = self.response_sender(request_id, encode_edit);
+        return self.send(Command::Edit(Edit {
+            channel_name,
+            user_id,
+            operation: Operation::Add,
+            value: points,
+            force: false,
+            response_sender,
+        }));
     }
 
-    fn handle_remove(&mut self, buffer: Vec<u8>) -> Result<Option<Vec<u8>>, MyError> {
-        let force = if buffer[0] == 0x01 { true } else { false };
+    fn handle_remove(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        if buffer.len() < 9 {
+            return Err(MyError::BufferError);
+        }
+
+        // Read force flag from the first byte
+        let force = buffer[0] == 0x01;
         // Read points from 8 first bytes
         let points = buf_to_u64(&buffer[1..9])?;
 
         // Read user ID into a string from remaining bytes
         let user_id = parse_user_id(buffer[9..].to_vec())?;
 
-        let (sender, receiver) = channel();
+        let response_sender = self.response_sender(request_id, encode_edit);
+        return self.send(Command::Edit(Edit {
+            channel_name,
+            user_id,
+            operation: Operation::Remove,
+            value: points,
+            force,
+            response_sender,
+        }));
+    }
 
-        self.request_sender
-            .send(Command::Edit(Edit {
-                channel_name: self.channel_name.clone(),
-                user_id: user_id,
-                operation: Operation::Remove,
-                value: points,
-                force: force,
-                response_sender: sender,
-            }))
-            .unwrap();
+    fn handle_set(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        if buffer.len() < 8 {
+            return Err(MyError::BufferError);
+        }
+
+        // Read points from 8 first bytes
+        let points = buf_to_u64(&buffer[0..8])?;
+
+        // Read user ID into a string from remaining bytes
+        let user_id = parse_user_id(buffer[8..].to_vec())?;
+
+        let response_sender = self.response_sender(request_id, encode_set);
+        return self.send(Command::Edit(Edit {
+            channel_name,
+            user_id,
+            operation: Operation::Set,
+            value: points,
+            force: false,
+            response_sender,
+        }));
+    }
 
-        let mut response = Vec::new();
+    fn handle_delete(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        if buffer.is_empty() {
+            return Err(MyError::BufferError);
+        }
+
+        // Read the flag from the first byte
+        let all_channels = buffer[0] == ALL_CHANNELS;
+
+        let user_ids = parse_user_id_bulk(buffer[1..].to_vec())?;
+
+        let response_sender = self.response_sender(request_id, encode_deleted_users);
+        return self.send(Command::Delete(Delete {
+            channel_name,
+            user_ids,
+            all_channels,
+            response_sender,
+        }));
+    }
+
+    fn handle_merge(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let mut reader = BodyReader::new(buffer);
+        let all_channels = reader.read_u8()? == ALL_CHANNELS;
+        let from_user_id = reader.read_user_id()?;
+        let to_user_id = reader.read_user_id()?;
+        reader.finish()?;
 
-        let (result_bool, user_points) = receiver.recv().unwrap();
-        let result = if result_bool { RESULT_OK } else { RESULT_ERR };
+        let response_sender = self.response_sender(request_id, encode_merged_users);
+        return self.send(Command::Merge(Merge {
+            channel_name,
+            from_user_id,
+            to_user_id,
+            all_channels,
+            response_sender,
+        }));
+    }
+
+    fn handle_reset(&mut self, request_id: u32, channel_name: String) -> Result<(), MyError> {
+        let response_sender = self.response_sender(request_id, encode_string);
+        return self.send(Command::Reset(Reset {
+            channel_name,
+            response_sender,
+        }));
+    }
 
-        let user_points_buf = u64_to_buf(user_points);
+    fn handle_close_season(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let mut reader = BodyReader::new(buffer);
+        let reset = reader.read_u8()? == RESET_BALANCES;
+        let season_id = parse_season_id(reader.rest())?;
 
-        response.push(result);
-        response.append(&mut user_points_buf.to_vec());
+        let response_sender = self.response_sender(request_id, encode_u64);
+        return self.send(Command::CloseSeason(CloseSeason {
+            channel_name,
+            season_id,
+            reset,
+            response_sender,
+        }));
+    }
+
+    fn handle_season_top(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let mut reader = BodyReader::new(buffer);
+        let limit = reader.read_u32()?.min(self.max_leaderboard_size);
+        let season_id = parse_season_id(reader.rest())?;
 
-        return Ok(Some(response));
+        let response_sender = self.response_sender(request_id, encode_leaderboard);
+        return self.send(Command::SeasonLeaderboard(SeasonLeaderboard {
+            channel_name,
+            season_id,
+            limit: limit as usize,
+            response_sender,
+        }));
     }
 
-    fn handle_rank(&mut self, buffer: Vec<u8>) -> Result<Option<Vec<u8>>, MyError> {
+    fn handle_season_rank(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let mut reader = BodyReader::new(buffer);
+        let season_id = parse_season_id(reader.read_string()?.into_bytes())?;
+        let user_id = parse_user_id(reader.rest())?;
+
+        let response_sender = self.response_sender(request_id, encode_season_rank);
+        return self.send(Command::SeasonRank(SeasonRank {
+            channel_name,
+            season_id,
+            user_id,
+            response_sender,
+        }));
+    }
+
+    fn handle_transfer(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let mut reader = BodyReader::new(buffer);
+        let amount = reader.read_u64()?;
+        let from_user_id = reader.read_user_id()?;
+        let to_user_id = reader.read_user_id()?;
+        reader.finish()?;
+
+        let response_sender = self.response_sender(request_id, encode_transfer);
+        return self.send(Command::Transfer(Transfer {
+            channel_name,
+            from_user_id,
+            to_user_id,
+            amount,
+            response_sender,
+        }));
+    }
+
+    fn handle_rank(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
         // Read user ID into a string from remaining bytes
-        let user_id = parse_user_id(buffer.to_vec())?;
+        let user_id = parse_user_id(buffer)?;
 
-        let (sender, receiver) = channel();
+        let response_sender = self.response_sender(request_id, encode_u64);
+        return self.send(Command::Rank(Rank {
+            channel_name,
+            user_id,
+            response_sender,
+        }));
+    }
 
-        self.request_sender
-            .send(Command::Rank(Rank {
-                channel_name: self.channel_name.clone(),
-                user_id: user_id,
-                response_sender: sender,
-            }))
-            .unwrap();
+    fn handle_top(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let mut reader = BodyReader::new(buffer);
+        let limit = reader.read_u32()?;
+
+        return self.send_leaderboard(request_id, channel_name, 0, limit);
+    }
+
+    fn handle_range(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let mut reader = BodyReader::new(buffer);
+        let offset = reader.read_u32()?;
+        let limit = reader.read_u32()?;
+
+        return self.send_leaderboard(request_id, channel_name, offset, limit);
+    }
+
+    fn send_leaderboard(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        offset: u32,
+        limit: u32,
+    ) -> Result<(), MyError> {
+        let limit = limit.min(self.max_leaderboard_size);
+
+        let response_sender = self.response_sender(request_id, encode_leaderboard);
+        return self.send(Command::Leaderboard(Leaderboard {
+            channel_name,
+            offset: offset as usize,
+            limit: limit as usize,
+            response_sender,
+        }));
+    }
+
+    fn handle_around(
+        &mut self,
+        request_id: u32,
+        channel_name: String,
+        buffer: Vec<u8>,
+    ) -> Result<(), MyError> {
+        let mut reader = BodyReader::new(buffer);
+        // The user and the users on both sides must fit in a leaderboard
+        let count = reader
+            .read_u32()?
+            .min(self.max_leaderboard_size.saturating_sub(1) / 2);
+        let user_id = parse_user_id(reader.rest())?;
+
+        let response_sender = self.response_sender(request_id, encode_leaderboard);
+        return self.send(Command::Around(Around {
+            channel_name,
+            user_id,
+            count: count as usize,
+            response_sender,
+        }));
+    }
+}
+
+fn encode_empty(_: ()) -> Vec<u8> {
+    return Vec::new();
+}
+
+fn encode_string(value: String) -> Vec<u8> {
+    return value.into_bytes();
+}
+
+fn encode_u64(value: u64) -> Vec<u8> {
+    return u64_to_buf(value).to_vec();
+}
+
+// Points and rank of every user
+fn encode_points_bulk(users: Vec<(u64, u64)>) -> Vec<u8> {
+    let mut response = Vec::with_capacity(users.len() * 16);
+    for (points, rank) in users {
+        response.extend_from_slice(&u64_to_buf(points));
+        response.extend_from_slice(&u64_to_buf(rank));
+    }
+
+    return response;
+}
+
+// Number of users that were edited, skipped and clamped
+fn encode_bulk_edit_summary(summary: BulkEditSummary) -> Vec<u8> {
+    let mut response = Vec::with_capacity(12);
+    response.extend_from_slice(&u32_to_buf(summary.applied));
+    response.extend_from_slice(&u32_to_buf(summary.skipped));
+    response.extend_from_slice(&u32_to_buf(summary.clamped));
+
+    return response;
+}
+
+// Points, channel name and user ID of every deleted user
+fn encode_deleted_users(users: ChannelResults<DeletedUser>) -> Vec<u8> {
+    let mut response = encode_failures(&users.failures);
+    for user in users.results {
+        response.extend_from_slice(&u64_to_buf(user.points));
+        response.extend_from_slice(user.channel_name.as_bytes());
+        response.push(b';');
+        response.extend_from_slice(user.user_id.as_bytes());
+        response.push(b';');
+    }
 
-        let user_rank = receiver.recv().unwrap();
+    return response;
+}
+
+// Points moved, new points and channel name of every channel the users were merged in
+fn encode_merged_users(users: ChannelResults<MergedUser>) -> Vec<u8> {
+    let mut response = encode_failures(&users.failures);
+    for user in users.results {
+        response.extend_from_slice(&u64_to_buf(user.points));
+        response.extend_from_slice(&u64_to_buf(user.new_points));
+        response.extend_from_slice(user.channel_name.as_bytes());
+        response.push(b';');
+    }
+
+    return response;
+}
+
+// Number of channels a command could not run in, followed by the error code and the channel name
+// of each
+fn encode_failures(failures: &[ChannelFailure]) -> Vec<u8> {
+    let mut response = u32_to_buf(failures.len() as u32).to_vec();
+    for failure in failures {
+        response.push(failure.error.code());
+        response.extend_from_slice(failure.channel_name.as_bytes());
+        response.push(b';');
+    }
+
+    return response;
+}
 
-        return Ok(Some(u64_to_buf(user_rank).to_vec()));
+// Result of a command, followed by the given points
+fn encode_result(result_bool: bool, points: &[u64]) -> Vec<u8> {
+    let result = if result_bool { RESULT_OK } else { RESULT_ERR };
+
+    let mut response = vec![result];
+    for p in points {
+        response.extend_from_slice(&u64_to_buf(*p));
     }
+
+    return response;
+}
+
+// Result of the edit, followed by the users points
+fn encode_edit((result_bool, _, user_points): (bool, u64, u64)) -> Vec<u8> {
+    return encode_result(result_bool, &[user_points]);
+}
+
+// Result of the set, followed by the users points before and after
+fn encode_set((result_bool, old_points, new_points): (bool, u64, u64)) -> Vec<u8> {
+    return encode_result(result_bool, &[old_points, new_points]);
+}
+
+// Result of the transfer, followed by the points of the sender and the receiver
+fn encode_transfer((result_bool, from_points, to_points): (bool, u64, u64)) -> Vec<u8> {
+    return encode_result(result_bool, &[from_points, to_points]);
+}
+
+// Like a leaderboard with only the user, or empty if the user was not in the season
+fn encode_season_rank(entry: Option<LeaderboardEntry>) -> Vec<u8> {
+    return encode_leaderboard(entry.into_iter().collect());
+}
+
+// The rank, points and user ID of every entry
+fn encode_leaderboard(entries: Vec<LeaderboardEntry>) -> Vec<u8> {
+    let mut response = Vec::new();
+    for entry in entries {
+        response.extend_from_slice(&u64_to_buf(entry.rank));
+        response.extend_from_slice(&u64_to_buf(entry.points));
+        response.extend_from_slice(entry.user_id.as_bytes());
+        response.push(b';');
+    }
+
+    return response;
 }
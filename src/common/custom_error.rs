@@ -1,7 +1,8 @@
 use std::fmt;
 use std::io;
 use std::string;
-use std::sync::mpsc;
+
+use common::*;
 
 pub struct WrongCommand {
     received_command: u8,
@@ -21,24 +22,74 @@ pub enum MyError {
     IoError(io::Error),
     ParseError(string::FromUtf8Error),
     WrongCommand(WrongCommand),
-    RecvError(mpsc::RecvError),
     SendError(String),
+    ChannelNameError(String),
+    BodyTooLarge(u32),
+    UnsupportedVersion(u32),
+    AuthenticationFailed,
+    UnknownCommand(u8),
+    UnknownChannel(String),
+    SeasonIdError(String),
+    UnknownSeason(String),
+    SeasonExists(String),
+    MalformedBody(&'static str),
+    PointsOverflow(String),
+    InternalError(String),
     BufferError,
 }
 
+impl MyError {
+    // Error code sent to the client in an error response
+    pub fn code(&self) -> u8 {
+        match self {
+            MyError::ParseError(_) => ERROR_BAD_UTF8,
+            MyError::BufferError => ERROR_SHORT_BUFFER,
+            MyError::UnknownCommand(_) | MyError::WrongCommand(_) => ERROR_UNKNOWN_COMMAND,
+            MyError::UnknownChannel(_) => ERROR_UNKNOWN_CHANNEL,
+            MyError::ChannelNameError(_) => ERROR_INVALID_CHANNEL_NAME,
+            MyError::BodyTooLarge(_) => ERROR_BODY_TOO_LARGE,
+            MyError::UnsupportedVersion(_) => ERROR_UNSUPPORTED_VERSION,
+            MyError::AuthenticationFailed => ERROR_AUTHENTICATION_FAILED,
+            MyError::SeasonIdError(_) => ERROR_INVALID_SEASON_ID,
+            MyError::UnknownSeason(_) => ERROR_UNKNOWN_SEASON,
+            MyError::SeasonExists(_) => ERROR_SEASON_EXISTS,
+            MyError::MalformedBody(_) => ERROR_MALFORMED_BODY,
+            MyError::PointsOverflow(_) => ERROR_POINTS_OVERFLOW,
+            MyError::IoError(_) | MyError::SendError(_) | MyError::InternalError(_) => {
+                ERROR_INTERNAL
+            }
+        }
+    }
+}
+
 impl fmt::Display for MyError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             MyError::IoError(e) => return fmt::Display::fmt(e, f),
-            MyError::RecvError(e) => return fmt::Display::fmt(e, f),
             MyError::ParseError(e) => fmt::Display::fmt(e, f),
             MyError::WrongCommand(e) => write!(
                 f,
                 "wrong command. got {:x}, expected {:x}",
                 e.received_command, e.expected_command
             ),
+            MyError::BodyTooLarge(size) => write!(f, "body of {} bytes is too large", size),
+            MyError::UnsupportedVersion(v) => write!(
+                f,
+                "protocol version {} is not supported, the server supports versions {} to {}",
+                v, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            MyError::AuthenticationFailed => write!(f, "authentication failed"),
+            MyError::UnknownCommand(c) => write!(f, "unknown command {:x}", c),
+            MyError::UnknownChannel(c) => write!(f, "unknown channel {}", c),
+            MyError::SeasonIdError(s) => write!(f, "invalid season ID: {:?}", s),
+            MyError::UnknownSeason(s) => write!(f, "unknown season {}", s),
+            MyError::SeasonExists(s) => write!(f, "season {} is already closed", s),
+            MyError::MalformedBody(e) => write!(f, "malformed body: {}", e),
+            MyError::PointsOverflow(u) => write!(f, "the points of {} would overflow", u),
+            MyError::InternalError(e) => write!(f, "internal error: {}", e),
             MyError::BufferError => write!(f, "buffer error"),
             MyError::SendError(e) => write!(f, "send error: {}", e),
+            MyError::ChannelNameError(e) => write!(f, "invalid channel name: {:?}", e),
         }
     }
 }
@@ -3,15 +3,171 @@ mod custom_error;
 pub use self::custom_error::MyError;
 pub use self::custom_error::WrongCommand;
 
+// Every command starts with a 1 byte command, a 4 byte request ID chosen by the client and the 4
+// byte size of the body, followed by the body.
+// Commands are handled in parallel, so responses may arrive in a different order than the
+// commands were sent. The request ID is used to match responses to commands
+//
+// The first command must be COMMAND_CONNECT. Its body is the 4 byte protocol version the client
+// speaks, the client name, the number of features the client wants followed by their names, and
+// finally the channel name. The client name and feature names are prefixed with their 1 byte size.
+// The server answers with the protocol version and the features it accepted, in the same format,
+// followed by a nonce prefixed with its 1 byte size, or rejects the connection with an error.
+// If the nonce is empty, the client is connected. Otherwise the client must authenticate with
+// COMMAND_AUTH before sending anything else.
+// Until the client is connected, bodies larger than MAX_HANDSHAKE_BODY_SIZE are refused with
+// ERROR_BODY_TOO_LARGE
 pub const COMMAND_CONNECT: u8 = 0x01;
 pub const COMMAND_GET: u8 = 0x02;
 pub const COMMAND_BULK_EDIT: u8 = 0x03;
 
+// Add points. Responds with the users new total
 pub const COMMAND_ADD: u8 = 0x04;
-// Try to remove points. If the user does not have enough points, return an error
+// Try to remove points. If the user does not have enough points, return an error along with
+// the users current points.
+// If the force flag is set, remove as many points as the user has instead
 pub const COMMAND_REMOVE: u8 = 0x05;
 
 pub const COMMAND_RANK: u8 = 0x06;
 
+// Answer the challenge from COMMAND_CONNECT. The body is the HMAC-SHA256 of the nonce, keyed with
+// the shared secret. The connection is closed if the answer is wrong
+pub const COMMAND_AUTH: u8 = 0x07;
+
+// Switch the connection to another channel. The body is the channel name.
+// Commands sent after this one are for the new channel, even if it can't be used. The response
+// says whether the channel is ready
+pub const COMMAND_SELECT_CHANNEL: u8 = 0x08;
+
+// Get the users with the most points. The body is the 4 byte number of users to get, which the
+// server may lower. Responds with the 8 byte rank, 8 byte points and the user ID followed by a ';'
+// of each user, in rank order
+pub const COMMAND_TOP: u8 = 0x09;
+
+// Get a page of the leaderboard. The body is the 4 byte zero-based position of the first user,
+// and the 4 byte number of users to get, which the server may lower. Responds like COMMAND_TOP
+pub const COMMAND_RANGE: u8 = 0x0A;
+
+// Get a user along with the users ranked right above and below them. The body is the 4 byte
+// number of users to get on each side, which the server may lower, followed by the user ID.
+// Responds like COMMAND_TOP
+pub const COMMAND_AROUND: u8 = 0x0B;
+
+// Move points from one user to another. The body is the 8 byte amount, followed by the user ID of
+// the sender and the user ID of the receiver, each followed by a ';'.
+// Responds with the result, and the points of the sender and the receiver after the transfer.
+// If the sender does not have enough points, the result is RESULT_ERR and nothing is moved. If the
+// points of the receiver would overflow, the transfer fails with ERROR_POINTS_OVERFLOW and nothing
+// is moved
+pub const COMMAND_TRANSFER: u8 = 0x0C;
+
+// Get the points and rank of many users. The body is the user IDs, each followed by a ';'.
+// Responds with the 8 byte points and 8 byte rank of each user, in the order they were asked for
+pub const COMMAND_GET_BULK: u8 = 0x0D;
+
+// Set the points of a user. The body is the 8 byte new points, followed by the user ID.
+// Responds with the result, and the users points before and after
+pub const COMMAND_SET: u8 = 0x0E;
+
+// Edit the points of many users by a different amount each. The body is a 1 byte flag, followed
+// by the 8 byte signed amount (positive for add, negative for remove) and the user ID followed by
+// a ';' of each user.
+// If the flag is BULK_EDIT_ACK, the response is sent once the edits are made, and holds the 4
+// byte number of users that were edited, skipped and clamped. Otherwise the response is empty and
+// only says the edits were accepted
+pub const COMMAND_BULK_EDIT_AMOUNTS: u8 = 0x0F;
+pub const BULK_EDIT_ACK: u8 = 0x01;
+
+// Delete users. The body is a 1 byte flag, followed by the user IDs, each followed by a ';'.
+// If the flag is ALL_CHANNELS, the users are deleted from every channel instead of only the
+// selected one. Responds with the channels the users could not be deleted from, followed by the
+// 8 byte points, the channel name followed by a ';' and the user ID followed by a ';' of each
+// user that was deleted. Users are deleted from the closed seasons of the channel too, which
+// rewrites the files of the seasons they were in. A user who is only in closed seasons is listed
+// with 0 points.
+// The channels that were left out are listed as the 4 byte number of channels, followed by the
+// 1 byte error code and the channel name followed by a ';' of each, whether the channel can't be
+// served or the delete failed in it. The list is only filled in for ALL_CHANNELS, the users are
+// still deleted from every other channel. Without the flag, an error is sent as an error response
+// instead.
+// Only the live database, journal and season files of the channel are changed. Copies the server
+// keeps next to them still hold the users: archives made by COMMAND_RESET (<channel>.archive-<time>), backups
+// made before migrating an older database (<channel>.v<version>), quarantined databases
+// (<channel>.corrupt-<time>) and damaged journals (<channel>.journal.damaged-<time>). Those have
+// to be removed by hand if the data must be gone
+pub const COMMAND_DELETE: u8 = 0x10;
+pub const ALL_CHANNELS: u8 = 0x01;
+
+// Move all points of a user to another user, and delete the first user. The body is a 1 byte
+// flag, followed by the user ID to merge from and the user ID to merge into, each followed by a
+// ';'. If the flag is ALL_CHANNELS, the users are merged in every channel instead of only the
+// selected one. Responds with the 8 byte points that were moved, the 8 byte points the user now
+// has and the channel name followed by a ';' of each channel the users were merged in. A merge
+// that would overflow the points of the user to merge into fails with ERROR_POINTS_OVERFLOW, and
+// leaves both users alone in that channel. The response starts with the channels the users could
+// not be merged in, like for COMMAND_DELETE. For ALL_CHANNELS, a channel where the merge would
+// overflow is listed there with ERROR_POINTS_OVERFLOW, and the users are still merged in every
+// other channel
+pub const COMMAND_MERGE: u8 = 0x11;
+
+// Remove every user from the selected channel, after archiving a copy of the channel. The body is
+// empty. Responds with the file name of the archive
+pub const COMMAND_RESET: u8 = 0x12;
+
+// Close a season of the selected channel, storing the final ranks under the season ID. The body is
+// a 1 byte flag, followed by the season ID. If the flag is RESET_BALANCES, every user is removed
+// from the channel once the season is closed. Responds with the 8 byte number of users in the
+// season.
+// Every season is written once to a read-only file of its own next to the channel database
+// (<channel>.season-<season ID>), and only read while a season command is handled
+pub const COMMAND_CLOSE_SEASON: u8 = 0x13;
+pub const RESET_BALANCES: u8 = 0x01;
+
+// Get the top N users of a closed season. The body is the 4 byte N, followed by the season ID.
+// Responds like COMMAND_TOP
+pub const COMMAND_SEASON_TOP: u8 = 0x14;
+
+// Get the final rank of a user in a closed season. The body is the 1 byte size of the season ID,
+// the season ID and the user ID. Responds with the 8 byte rank, the 8 byte points and the user ID
+// followed by a ';', or with an empty body if the user was not in the season
+pub const COMMAND_SEASON_RANK: u8 = 0x15;
+
+// Newest protocol version the server speaks, and the oldest one it still accepts
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Largest body accepted before the client is connected, so clients that haven't authenticated
+// can't make the server hold on to much
+pub const MAX_HANDSHAKE_BODY_SIZE: u32 = 512;
+
 pub const RESULT_OK: u8 = 0x00;
 pub const RESULT_ERR: u8 = 0x01;
+
+// Every command is answered with a response, which starts with a 1 byte status, the request ID of
+// the command and the 4 byte size of the body, followed by the body
+pub const RESPONSE_OK: u8 = 0x00;
+// The body of an error response is a 1 byte error code, followed by a description of the error.
+// The connection stays open after an error response
+pub const RESPONSE_ERR: u8 = 0x01;
+
+pub const ERROR_BAD_UTF8: u8 = 0x01;
+// The body is too short for the command
+pub const ERROR_SHORT_BUFFER: u8 = 0x02;
+pub const ERROR_UNKNOWN_COMMAND: u8 = 0x03;
+// The channel does not exist, or can't be served right now
+pub const ERROR_UNKNOWN_CHANNEL: u8 = 0x04;
+// Something went wrong in the server, the command may be retried
+pub const ERROR_INTERNAL: u8 = 0x05;
+pub const ERROR_INVALID_CHANNEL_NAME: u8 = 0x06;
+pub const ERROR_BODY_TOO_LARGE: u8 = 0x07;
+// The client speaks a protocol version the server doesn't accept
+pub const ERROR_UNSUPPORTED_VERSION: u8 = 0x08;
+pub const ERROR_AUTHENTICATION_FAILED: u8 = 0x09;
+pub const ERROR_INVALID_SEASON_ID: u8 = 0x0A;
+pub const ERROR_UNKNOWN_SEASON: u8 = 0x0B;
+// Seasons can't be closed twice under the same ID
+pub const ERROR_SEASON_EXISTS: u8 = 0x0C;
+// The body is long enough, but its fields don't make sense
+pub const ERROR_MALFORMED_BODY: u8 = 0x0D;
+// The command would give a user more points than fit in 8 bytes
+pub const ERROR_POINTS_OVERFLOW: u8 = 0x0E;
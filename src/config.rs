@@ -0,0 +1,209 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time;
+
+use getopts::Options;
+use log::LevelFilter;
+
+// Read if no config file is given. Unlike a given config file, this one doesn't have to exist
+static DEFAULT_CONFIG_PATH: &str = "points.toml";
+
+// Environment variables are named after the settings, with this prefix. e.g. POINTS_HOST
+static ENV_PREFIX: &str = "POINTS_";
+
+// Every setting, along with its description for --help.
+// Settings can be overridden with an environment variable and a command-line option of the same
+// name, e.g. save_interval can be set with POINTS_SAVE_INTERVAL and --save-interval
+static SETTINGS: &[(&str, &str)] = &[
+    ("host", "Address to listen for connections on"),
+    ("db_path", "Directory to store channel databases in"),
+    ("save_interval", "Seconds between saves of every channel"),
+    ("log_level", "One of off, error, warn, info, debug or trace"),
+    (
+        "max_body_size",
+        "Largest request body a client may send, in bytes",
+    ),
+    (
+        "max_connections",
+        "Most clients that may be connected at the same time. The open file limit must leave room \
+         for these, plus one file per channel",
+    ),
+    (
+        "max_leaderboard_size",
+        "Most users a leaderboard command may return",
+    ),
+    (
+        "secret",
+        "Shared secret clients must authenticate with. If empty, every client is accepted",
+    ),
+];
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub db_path: String,
+    pub save_interval: u64,
+    pub log_level: String,
+    pub max_body_size: u32,
+    pub max_connections: usize,
+    pub max_leaderboard_size: u32,
+    pub secret: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return Config {
+            host: "127.0.0.1:54321".to_string(),
+            db_path: "db".to_string(),
+            save_interval: 10 * 60,
+            log_level: "info".to_string(),
+            max_body_size: 16 * 1024 * 1024,
+            max_connections: 10000,
+            max_leaderboard_size: 100,
+            secret: String::new(),
+        };
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    return value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {:?}", key, value));
+}
+
+impl Config {
+    // Builds the configuration from, in order of precedence:
+    // 1. Command-line options
+    // 2. Environment variables
+    // 3. The config file
+    // 4. Defaults
+    pub fn load() -> Result<Config, String> {
+        let args: Vec<String> = env::args().collect();
+
+        let mut opts = Options::new();
+        opts.optopt(
+            "c",
+            "config",
+            &format!("Config file to read (default {})", DEFAULT_CONFIG_PATH),
+            "FILE",
+        );
+        for (key, description) in SETTINGS {
+            opts.optopt("", &key.replace('_', "-"), description, "VALUE");
+        }
+        opts.optflag("h", "help", "Print this help");
+
+        let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+
+        if matches.opt_present("help") {
+            print!("{}", opts.usage(&format!("Usage: {} [options]", args[0])));
+            process::exit(0);
+        }
+
+        let config_path = matches
+            .opt_str("config")
+            .or_else(|| env::var(format!("{}CONFIG", ENV_PREFIX)).ok());
+
+        let mut config = match config_path {
+            Some(path) => Config::read(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::read(DEFAULT_CONFIG_PATH)?,
+            None => Config::default(),
+        };
+
+        for (key, _) in SETTINGS {
+            if let Ok(value) = env::var(format!("{}{}", ENV_PREFIX, key.to_uppercase())) {
+                config.set(key, &value)?;
+            }
+        }
+
+        for (key, _) in SETTINGS {
+            if let Some(value) = matches.opt_str(&key.replace('_', "-")) {
+                config.set(key, &value)?;
+            }
+        }
+
+        config.validate()?;
+
+        return Ok(config);
+    }
+
+    fn read(path: &str) -> Result<Config, String> {
+        let mut buf = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut buf))
+            .map_err(|e: io::Error| format!("unable to read {}: {}", path, e))?;
+
+        return toml::from_str(&buf).map_err(|e| format!("unable to parse {}: {}", path, e));
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "host" => self.host = value.to_string(),
+            "db_path" => self.db_path = value.to_string(),
+            "save_interval" => self.save_interval = parse(key, value)?,
+            "log_level" => self.log_level = value.to_string(),
+            "max_body_size" => self.max_body_size = parse(key, value)?,
+            "max_connections" => self.max_connections = parse(key, value)?,
+            "max_leaderboard_size" => self.max_leaderboard_size = parse(key, value)?,
+            "secret" => self.secret = value.to_string(),
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+
+        return Ok(());
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        parse::<LevelFilter>("log_level", &self.log_level)?;
+
+        if self.save_interval == 0 {
+            return Err("save_interval must be at least 1 second".to_string());
+        }
+
+        if self.max_connections == 0 {
+            return Err("max_connections must be at least 1".to_string());
+        }
+
+        return Ok(());
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        // Checked by validate
+        return self.log_level.parse().unwrap_or(LevelFilter::Info);
+    }
+
+    pub fn secret(&self) -> Option<&[u8]> {
+        if self.secret.is_empty() {
+            return None;
+        }
+
+        return Some(self.secret.as_bytes());
+    }
+
+    pub fn save_interval(&self) -> time::Duration {
+        return time::Duration::from_secs(self.save_interval);
+    }
+}
+
+// Prints the configuration in the config file format
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "host = {:?}", self.host)?;
+        writeln!(f, "db_path = {:?}", self.db_path)?;
+        writeln!(f, "save_interval = {}", self.save_interval)?;
+        writeln!(f, "log_level = {:?}", self.log_level)?;
+        writeln!(f, "max_body_size = {}", self.max_body_size)?;
+        writeln!(f, "max_connections = {}", self.max_connections)?;
+        writeln!(f, "max_leaderboard_size = {}", self.max_leaderboard_size)?;
+        // Never print the secret itself
+        match self.secret() {
+            None => write!(f, "secret = \"\""),
+            Some(_) => write!(f, "secret = \"<hidden>\""),
+        }
+    }
+}
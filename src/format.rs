@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use bincode::{deserialize, serialize};
+
+use points::ChannelPoints;
+use ranks::RankIndex;
+use season::Season;
+use utils::*;
+
+// Every database file starts with these bytes, followed by the format version
+const MAGIC: &[u8; 4] = b"PJPT";
+
+const HEADER_SIZE: usize = 8;
+
+// Version 0: No header, the user map and the rank vector
+// Version 1: Header, the rank index
+// Version 2: Header, the rank index and the closed seasons
+// Version 3: Header, the rank index. Closed seasons are stored in files of their own
+pub const CURRENT_VERSION: u32 = 3;
+
+// Every season file starts with these bytes, followed by the format version of the season
+const SEASON_MAGIC: &[u8; 4] = b"PJSN";
+
+// Version 1: Header, the rank index at the end of the season
+const SEASON_VERSION: u32 = 1;
+
+pub enum FormatError {
+    // The file was written by a newer version of the server
+    UnknownVersion(u32),
+    Corrupt(bincode::Error),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::UnknownVersion(v) => write!(f, "unknown format version {}", v),
+            FormatError::Corrupt(e) => write!(f, "corrupt database: {}", e),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DatabaseV0 {
+    // Key = User ID
+    // Value = Rank. Was never kept up to date, the rank vector is the source of truth
+    _user_id_to_rank: HashMap<String, u64>,
+
+    ranks: Vec<(u64, String)>,
+}
+
+impl DatabaseV0 {
+    fn migrate(self) -> ChannelPoints {
+        let mut ranks = RankIndex::new();
+        for (points, user_id) in self.ranks {
+            ranks.insert(points, user_id);
+        }
+
+        return ChannelPoints::from_ranks(ranks);
+    }
+}
+
+#[derive(Deserialize)]
+struct DatabaseV1 {
+    ranks: RankIndex,
+}
+
+impl DatabaseV1 {
+    fn migrate(self) -> ChannelPoints {
+        return ChannelPoints::from_ranks(self.ranks);
+    }
+}
+
+#[derive(Deserialize)]
+struct DatabaseV2 {
+    ranks: RankIndex,
+
+    // Key = Season ID
+    seasons: BTreeMap<String, Season>,
+}
+
+impl DatabaseV2 {
+    fn migrate(self) -> (ChannelPoints, BTreeMap<String, Season>) {
+        return (ChannelPoints::from_ranks(self.ranks), self.seasons);
+    }
+}
+
+pub fn encode(c: &ChannelPoints) -> bincode::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&u32_to_buf(CURRENT_VERSION));
+    buf.append(&mut serialize(c)?);
+
+    return Ok(buf);
+}
+
+// Decodes a database of any known version, migrating it to the current version.
+// Returns the database along with the version it was stored in, and the closed seasons of
+// versions that stored them in the database. Those must be moved to files of their own
+pub fn decode(buf: &[u8]) -> Result<(ChannelPoints, u32, BTreeMap<String, Season>), FormatError> {
+    if buf.len() < HEADER_SIZE || &buf[0..4] != MAGIC {
+        let v0: DatabaseV0 = deserialize(buf).map_err(FormatError::Corrupt)?;
+        return Ok((v0.migrate(), 0, BTreeMap::new()));
+    }
+
+    let version = buf_to_u32_unsafe(&buf[4..8]);
+    let body = &buf[HEADER_SIZE..];
+
+    match version {
+        1 => {
+            let v1: DatabaseV1 = deserialize(body).map_err(FormatError::Corrupt)?;
+            return Ok((v1.migrate(), version, BTreeMap::new()));
+        }
+        2 => {
+            let v2: DatabaseV2 = deserialize(body).map_err(FormatError::Corrupt)?;
+            let (c, seasons) = v2.migrate();
+            return Ok((c, version, seasons));
+        }
+        3 => {
+            let c = deserialize(body).map_err(FormatError::Corrupt)?;
+            return Ok((c, version, BTreeMap::new()));
+        }
+        _ => return Err(FormatError::UnknownVersion(version)),
+    }
+}
+
+pub fn encode_season(season: &Season) -> bincode::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(SEASON_MAGIC);
+    buf.extend_from_slice(&u32_to_buf(SEASON_VERSION));
+    buf.append(&mut serialize(season)?);
+
+    return Ok(buf);
+}
+
+pub fn decode_season(buf: &[u8]) -> Result<Season, FormatError> {
+    if buf.len() < HEADER_SIZE || &buf[0..4] != SEASON_MAGIC {
+        return Err(FormatError::Corrupt(Box::new(bincode::ErrorKind::Custom(
+            "not a season file".to_string(),
+        ))));
+    }
+
+    match buf_to_u32_unsafe(&buf[4..8]) {
+        SEASON_VERSION => {
+            return deserialize(&buf[HEADER_SIZE..]).map_err(FormatError::Corrupt);
+        }
+        version => return Err(FormatError::UnknownVersion(version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The points in rank order, as every version stores them
+    fn ranks() -> Vec<(u64, String)> {
+        return vec![(10, "a".to_string()), (5, "b".to_string())];
+    }
+
+    fn with_header(version: u32, body: Vec<u8>) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&u32_to_buf(version));
+        buf.extend(body);
+
+        return buf;
+    }
+
+    fn expected() -> Vec<u8> {
+        let mut index = RankIndex::new();
+        for (points, user_id) in ranks() {
+            index.insert(points, user_id);
+        }
+
+        return encode(&ChannelPoints::from_ranks(index)).unwrap();
+    }
+
+    fn decoded(buf: &[u8]) -> (Vec<u8>, u32) {
+        match decode(buf) {
+            Err(e) => panic!("unable to decode: {}", e),
+            Ok((c, version, seasons)) => {
+                assert!(seasons.is_empty());
+                return (encode(&c).unwrap(), version);
+            }
+        }
+    }
+
+    fn season_entries(season: &Season) -> Vec<(u64, String)> {
+        return season
+            .ranks()
+            .iter()
+            .map(|(points, user_id)| (points, user_id.to_string()))
+            .collect();
+    }
+
+    #[test]
+    fn decodes_version_0() {
+        let mut user_id_to_rank = HashMap::new();
+        user_id_to_rank.insert("a".to_string(), 1u64);
+        let buf = serialize(&(user_id_to_rank, ranks())).unwrap();
+
+        assert_eq!(decoded(&buf), (expected(), 0));
+    }
+
+    #[test]
+    fn decodes_version_1() {
+        let buf = with_header(1, serialize(&ranks()).unwrap());
+
+        assert_eq!(decoded(&buf), (expected(), 1));
+    }
+
+    #[test]
+    fn decodes_version_2_with_its_seasons() {
+        let mut seasons = BTreeMap::new();
+        seasons.insert("s1".to_string(), vec![(3u64, "c".to_string())]);
+        let buf = with_header(2, serialize(&(ranks(), seasons)).unwrap());
+
+        match decode(&buf) {
+            Err(e) => panic!("unable to decode: {}", e),
+            Ok((c, version, seasons)) => {
+                assert_eq!(encode(&c).unwrap(), expected());
+                assert_eq!(version, 2);
+                assert_eq!(seasons.len(), 1);
+                assert_eq!(season_entries(&seasons["s1"]), vec![(3, "c".to_string())]);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_current_version() {
+        assert_eq!(decoded(&expected()), (expected(), CURRENT_VERSION));
+    }
+
+    #[test]
+    fn refuses_unknown_versions() {
+        let buf = with_header(CURRENT_VERSION + 1, serialize(&ranks()).unwrap());
+
+        match decode(&buf) {
+            Err(FormatError::UnknownVersion(v)) => assert_eq!(v, CURRENT_VERSION + 1),
+            _ => panic!("decoded a database of an unknown version"),
+        }
+    }
+
+    #[test]
+    fn reports_corrupt_databases() {
+        let mut buf = expected();
+        buf.truncate(buf.len() - 1);
+
+        match decode(&buf) {
+            Err(FormatError::Corrupt(_)) => {}
+            _ => panic!("decoded a truncated database"),
+        }
+    }
+
+    #[test]
+    fn season_round_trip() {
+        let mut index = RankIndex::new();
+        for (points, user_id) in ranks() {
+            index.insert(points, user_id);
+        }
+        let buf = encode_season(&Season::new(index)).unwrap();
+
+        match decode_season(&buf) {
+            Err(e) => panic!("unable to decode season: {}", e),
+            Ok(season) => assert_eq!(season_entries(&season), ranks()),
+        }
+    }
+
+    #[test]
+    fn refuses_databases_as_seasons() {
+        match decode_season(&expected()) {
+            Err(FormatError::Corrupt(_)) => {}
+            _ => panic!("decoded a database as a season"),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use common::*;
+use parse::*;
+use utils::*;
+
+// Optional protocol features a client may ask for
+const SUPPORTED_FEATURES: &[&str] = &[];
+
+// Body of the COMMAND_CONNECT command
+#[derive(Debug)]
+pub struct Handshake {
+    pub protocol_version: u32,
+
+    // Name of the bot connecting, used for logging
+    pub client_name: String,
+
+    pub features: Vec<String>,
+
+    pub channel_name: String,
+}
+
+impl Handshake {
+    pub fn parse(buffer: Vec<u8>) -> Result<Handshake, MyError> {
+        let mut reader = BodyReader::new(buffer);
+
+        let protocol_version = reader.read_u32()?;
+        let client_name = reader.read_string()?;
+
+        let feature_count = reader.read_u8()?;
+        let mut features = Vec::with_capacity(feature_count as usize);
+        for _ in 0..feature_count {
+            features.push(reader.read_string()?);
+        }
+
+        let channel_name = parse_channel_name(reader.rest())?;
+
+        return Ok(Handshake {
+            protocol_version,
+            client_name,
+            features,
+            channel_name,
+        });
+    }
+
+    // Picks the protocol version and features to use for the connection.
+    // The client sends the newest version it speaks, and gets the newest version both sides speak.
+    // Features the server doesn't know are left out
+    pub fn negotiate(self) -> Result<Handshake, MyError> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(MyError::UnsupportedVersion(self.protocol_version));
+        }
+
+        let features = self
+            .features
+            .into_iter()
+            .filter(|f| SUPPORTED_FEATURES.contains(&f.as_str()))
+            .collect();
+
+        return Ok(Handshake {
+            protocol_version: self.protocol_version.min(PROTOCOL_VERSION),
+            client_name: self.client_name,
+            features,
+            channel_name: self.channel_name,
+        });
+    }
+
+    // The protocol version, followed by the number of features and their names, and the nonce the
+    // client must authenticate with
+    pub fn encode_reply(&self, nonce: &[u8]) -> Vec<u8> {
+        let mut reply = u32_to_buf(self.protocol_version).to_vec();
+
+        reply.push(self.features.len() as u8);
+        for feature in &self.features {
+            reply.push(feature.len() as u8);
+            reply.extend_from_slice(feature.as_bytes());
+        }
+
+        reply.push(nonce.len() as u8);
+        reply.extend_from_slice(nonce);
+
+        return reply;
+    }
+}
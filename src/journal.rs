@@ -0,0 +1,309 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+
+use bincode::{deserialize, serialize};
+
+use utils::*;
+
+// Every record starts with the size of its body and a checksum of the body
+const RECORD_HEADER_SIZE: usize = 8;
+
+// Added to the name of a copy of a damaged journal, followed by a timestamp
+const DAMAGED_SUFFIX: &str = ".damaged-";
+
+// A change made to the points of a channel.
+// Changes hold the resulting values rather than the edit that caused them, so replaying a
+// change that is already part of the database does nothing
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum Change {
+    // User ID, and the points the user has after the change
+    Set(String, u64),
+    // User ID of a user that was deleted
+    Remove(String),
+    // Every user was deleted
+    Clear,
+    // ID of a season that was closed with the ranks at the time of the change.
+    // Only written before seasons were stored in files of their own, and still replayed from
+    // journals of that time
+    CloseSeason(String),
+    // User ID of a user that was deleted from every closed season. Only written before seasons
+    // were stored in files of their own
+    RemoveFromSeasons(String),
+}
+
+// Append-only log of the changes made to a channel since its database was last saved
+#[derive(Debug)]
+pub struct Journal {
+    path: String,
+    file: File,
+}
+
+impl Journal {
+    // Opens the journal at the given path, and returns it along with every complete record it
+    // contains. An incomplete record at the end of the journal, left behind by a crash in the
+    // middle of a write, is cut off. A damaged record is cut off along with everything after it,
+    // after the journal is copied aside so the records after it can be recovered by hand
+    pub fn open(path: &str) -> io::Result<(Journal, Vec<Vec<Change>>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+
+        while offset < buf.len() {
+            match read_record(&buf[offset..]) {
+                None => {
+                    if record_size(&buf[offset..]).is_some() {
+                        // A crash only leaves an incomplete record behind, so the journal was
+                        // damaged some other way
+                        let copy_path = copy_damaged(path, &buf)?;
+                        error!(
+                            "Ignoring {} bytes starting with a damaged record in {}, copied \
+                             the journal to {}",
+                            buf.len() - offset,
+                            path,
+                            copy_path
+                        );
+                    } else {
+                        warn!(
+                            "Ignoring {} bytes of incomplete records at the end of {}",
+                            buf.len() - offset,
+                            path
+                        );
+                    }
+                    file.set_len(offset as u64)?;
+                    file.sync_all()?;
+                    break;
+                }
+                Some((changes, record_size)) => {
+                    records.push(changes);
+                    offset += record_size;
+                }
+            }
+        }
+
+        let journal = Journal {
+            path: path.to_string(),
+            file,
+        };
+
+        return Ok((journal, records));
+    }
+
+    // Writes the changes as one record, and waits until the record is on disk
+    pub fn append(&self, changes: &[Change]) -> io::Result<()> {
+        let body = serialize(changes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + body.len());
+        record.extend_from_slice(&u32_to_buf(body.len() as u32));
+        record.extend_from_slice(&u32_to_buf(checksum(&body)));
+        record.extend_from_slice(&body);
+
+        let size = self.file.metadata()?.len();
+        let result = (&self.file)
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data());
+
+        if result.is_err() {
+            // Records written after part of this one would look damaged
+            let _ = self.file.set_len(size);
+        }
+
+        return result;
+    }
+
+    // Removes every record. Called once the changes are part of a saved database
+    pub fn clear(&self) -> io::Result<()> {
+        self.file.set_len(0)?;
+
+        return self.file.sync_all();
+    }
+
+    pub fn path(&self) -> &str {
+        return &self.path;
+    }
+}
+
+// Returns the changes in the record at the start of the buffer, and the size of the record.
+// Returns None if the record is incomplete or damaged
+fn read_record(buf: &[u8]) -> Option<(Vec<Change>, usize)> {
+    let record_size = record_size(buf)?;
+    let body_checksum = buf_to_u32_unsafe(&buf[4..8]);
+
+    let body = &buf[RECORD_HEADER_SIZE..record_size];
+    if checksum(body) != body_checksum {
+        return None;
+    }
+
+    match deserialize(body) {
+        Err(_) => return None,
+        Ok(changes) => return Some((changes, record_size)),
+    }
+}
+
+// Size of the record at the start of the buffer. Returns None if the record is incomplete
+fn record_size(buf: &[u8]) -> Option<usize> {
+    if buf.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+
+    let record_size = RECORD_HEADER_SIZE + buf_to_u32_unsafe(&buf[0..4]) as usize;
+    if buf.len() < record_size {
+        return None;
+    }
+
+    return Some(record_size);
+}
+
+// Writes a copy of a damaged journal next to it. Returns the path of the copy
+fn copy_damaged(path: &str, buf: &[u8]) -> io::Result<String> {
+    let copy_path = timestamped_path(path, DAMAGED_SUFFIX);
+
+    let mut file = File::create(&copy_path)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+
+    return Ok(copy_path);
+}
+
+// 32-bit FNV-1a
+fn checksum(buf: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in buf {
+        hash ^= u32::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+
+    return hash;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    fn damaged_copies(path: &str) -> Vec<String> {
+        let path = Path::new(path);
+        let prefix = format!(
+            "{}{}",
+            path.file_name().unwrap().to_str().unwrap(),
+            DAMAGED_SUFFIX
+        );
+
+        let mut copies: Vec<String> = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|file_name| file_name.starts_with(&prefix))
+            .collect();
+        copies.sort();
+
+        return copies;
+    }
+
+    #[test]
+    fn records_are_replayed() {
+        let path = test_path("records_are_replayed", "journal");
+
+        let (journal, records) = Journal::open(&path).unwrap();
+        assert!(records.is_empty());
+        journal.append(&[Change::Set("a".to_string(), 1)]).unwrap();
+        journal
+            .append(&[
+                Change::Set("b".to_string(), 2),
+                Change::Remove("a".to_string()),
+            ])
+            .unwrap();
+        drop(journal);
+
+        let (_, records) = Journal::open(&path).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                vec![Change::Set("a".to_string(), 1)],
+                vec![
+                    Change::Set("b".to_string(), 2),
+                    Change::Remove("a".to_string())
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn incomplete_tail_is_cut_off() {
+        let path = test_path("incomplete_tail_is_cut_off", "journal");
+
+        let (journal, _) = Journal::open(&path).unwrap();
+        journal.append(&[Change::Clear]).unwrap();
+        let complete_size = fs::metadata(&path).unwrap().len();
+        drop(journal);
+
+        // A record that claims more bytes than were written before the crash
+        let mut torn = u32_to_buf(100).to_vec();
+        torn.extend_from_slice(&[0; 10]);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&torn)
+            .unwrap();
+
+        let (_, records) = Journal::open(&path).unwrap();
+        assert_eq!(records, vec![vec![Change::Clear]]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete_size);
+        assert!(damaged_copies(&path).is_empty());
+    }
+
+    #[test]
+    fn damaged_journal_is_copied_aside() {
+        let path = test_path("damaged_journal_is_copied_aside", "journal");
+
+        let (journal, _) = Journal::open(&path).unwrap();
+        journal.append(&[Change::Set("a".to_string(), 1)]).unwrap();
+        let first_size = fs::metadata(&path).unwrap().len();
+        journal.append(&[Change::Set("b".to_string(), 2)]).unwrap();
+        drop(journal);
+
+        // Damage the body of the second record, the first one is still replayed
+        let mut damaged = fs::read(&path).unwrap();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xFF;
+        fs::write(&path, &damaged).unwrap();
+
+        let (_, records) = Journal::open(&path).unwrap();
+        assert_eq!(records, vec![vec![Change::Set("a".to_string(), 1)]]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), first_size);
+
+        let copies = damaged_copies(&path);
+        assert_eq!(copies.len(), 1);
+        let copy_path = Path::new(&path).with_file_name(&copies[0]);
+        assert_eq!(fs::read(copy_path).unwrap(), damaged);
+
+        // A journal damaged again in the same second doesn't replace the first copy
+        fs::write(&path, &damaged).unwrap();
+        Journal::open(&path).unwrap();
+        assert_eq!(damaged_copies(&path).len(), 2);
+    }
+
+    #[test]
+    fn cleared_journal_is_empty() {
+        let path = test_path("cleared_journal_is_empty", "journal");
+
+        let (journal, _) = Journal::open(&path).unwrap();
+        journal.append(&[Change::Clear]).unwrap();
+        journal.clear().unwrap();
+        journal.append(&[Change::Remove("a".to_string())]).unwrap();
+        drop(journal);
+
+        let (_, records) = Journal::open(&path).unwrap();
+        assert_eq!(records, vec![vec![Change::Remove("a".to_string())]]);
+    }
+}
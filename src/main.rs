@@ -1,24 +1,35 @@
-use std::net::TcpListener;
+#![allow(clippy::needless_return)]
 
 use std::collections::HashMap;
 use std::sync::mpsc::channel;
-use std::{process, thread, time};
+use std::{process, thread};
 
 extern crate chrono;
 
+mod auth;
 mod common;
+mod config;
+mod handshake;
+use self::config::Config;
 
 mod parse;
 mod read;
 mod utils;
 
 mod client;
-use self::client::Client;
 use self::client::Command;
 
+mod server;
+use self::server::Server;
+
 mod points;
 use self::points::Points;
 
+mod format;
+mod journal;
+mod ranks;
+mod season;
+
 extern crate serde;
 
 #[allow(unused_imports)]
@@ -29,26 +40,59 @@ extern crate bincode;
 
 extern crate ctrlc;
 
-static SAVE_INTERVAL: time::Duration = time::Duration::from_millis(10 * 1000 * 60);
-static DB_PATH: &'static str = "db";
-static HOST: &'static str = "127.0.0.1:54321";
+extern crate getopts;
+extern crate toml;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate mio;
+
+extern crate hmac;
+extern crate rand;
+extern crate sha2;
 
 pub type ChannelPointMap = HashMap<String, u64>;
 pub type PointMap = HashMap<String, ChannelPointMap>;
 
 fn main() {
-    let points = match Points::load(DB_PATH) {
+    let config = match Config::load() {
         Err(e) => {
-            println!("Error loading database: {}", e);
+            eprintln!("Error loading configuration: {}", e);
+            process::exit(1);
+        }
+        Ok(c) => c,
+    };
+
+    env_logger::Builder::new()
+        .filter(None, config.log_level())
+        .init();
+
+    println!("Configuration:\n{}", config);
+
+    if config.secret().is_none() {
+        warn!("No secret is configured, every client that connects is accepted");
+    }
+
+    let mut points = match Points::load(&config.db_path) {
+        Err(e) => {
+            error!("Error loading database: {}", e);
             return;
         }
         Ok(p) => p,
     };
 
-    let listener = TcpListener::bind(HOST).unwrap();
-
     let (sender, receiver) = channel();
 
+    let mut server = match Server::new(&config, sender.clone()) {
+        Err(e) => {
+            error!("Error listening on {}: {}", config.host, e);
+            return;
+        }
+        Ok(s) => s,
+    };
+
     let ctrl_sender_copy = sender.clone();
 
     // Points handler will have two data structures:
@@ -56,52 +100,36 @@ fn main() {
     // 2. A sorted list by points, containing the points
 
     // Initialize points map handler
-    thread::spawn(move || {
-        loop {
-            use Command::*;
-
-            match receiver.recv() {
-                Err(_) => continue,
-                Ok(cmd) => {
-                    println!("Before cmd match");
-                    let forward = match &cmd {
-                        GetPoints(ref c) => Some(c.channel_name.clone()),
-                        BulkEdit(ref c) => Some(c.channel_name.clone()),
-                        Edit(ref c) => Some(c.channel_name.clone()),
-                        Rank(ref c) => Some(c.channel_name.clone()),
-                        SavePoints => {
-                            /*
-                        let utc: DateTime<Utc> = Utc::now();
-                        save_points(&mut points).unwrap();
-                        let utc2 = Utc::now();
-                        println!("saving took {}", utc2 - utc);
-                        */
-                            println!("unimplemented");
-                            None
-                        }
-                        Quit(_sender) => {
-                            /*
-                        let utc: DateTime<Utc> = Utc::now();
-                        save_points(&mut points).unwrap();
-                        let utc2 = Utc::now();
-                        println!("saving took {}", utc2 - utc);
-                        sender.send(()).unwrap();
-                        */
-                            println!("unimplemented");
-                            None
-                        }
-                    };
-                    println!("After cmd match");
-
-                    match forward {
-                        None => {}
-                        Some(channel_name) => {
-                            println!("Forwarding command {:?}", cmd);
-                            points.forward(channel_name, cmd);
-                        }
-                    }
-                }
+    thread::spawn(move || loop {
+        use Command::*;
+
+        match receiver.recv() {
+            Err(_) => continue,
+            Ok(SavePoints(sender)) => {
+                points.save(sender);
+            }
+            Ok(Quit(sender)) => {
+                points.quit();
+                sender.send(()).unwrap();
+                break;
+            }
+            Ok(Connect(c)) => {
+                let result = points.connect(&c.channel_name);
+                c.response_sender.send(result);
+            }
+            Ok(Delete(c)) if c.all_channels => {
+                points.delete_from_all(c);
             }
+            Ok(Merge(c)) if c.all_channels => {
+                points.merge_in_all(c);
+            }
+            Ok(cmd) => match cmd.channel_name().map(|c| c.to_string()) {
+                None => {}
+                Some(channel_name) => {
+                    debug!("Forwarding command {:?}", cmd);
+                    points.forward(channel_name, cmd);
+                }
+            },
         }
     });
 
@@ -113,33 +141,23 @@ fn main() {
         receiver.recv().unwrap();
 
         process::exit(0x0);
-    }).expect("Error setting Ctrl-C handler");
+    })
+    .expect("Error setting Ctrl-C handler");
 
     // Initialize occasional sender thread
     let sender_copy = sender.clone();
+    let save_interval = config.save_interval();
     thread::spawn(move || loop {
-        thread::sleep(SAVE_INTERVAL);
-        sender_copy.send(Command::SavePoints).unwrap();
+        thread::sleep(save_interval);
+
+        let (sender, receiver) = channel();
+        sender_copy.send(Command::SavePoints(sender)).unwrap();
+
+        receiver.recv().unwrap();
     });
 
     // Start listening for connections
-    for stream_result in listener.incoming() {
-        match stream_result {
-            Err(e) => println!("Error accepting connection: {}", e),
-            Ok(mut stream) => {
-                let sender_copy = sender.clone();
-                thread::spawn(move || {
-                    let result = Client::new(stream, sender_copy);
-                    match result {
-                        Err(e) => {
-                            println!("Error connecting to client: {}", e);
-                        }
-                        Ok(mut client) => {
-                            client.run();
-                        }
-                    }
-                });
-            }
-        }
+    if let Err(e) = server.run() {
+        error!("Error running server: {}", e);
     }
 }
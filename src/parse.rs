@@ -1,37 +1,292 @@
-use std::io;
-use std::io::prelude::*;
-use std::io::Read;
-
 use common::MyError;
+use utils::*;
 
+// User IDs are followed by a ';' in responses that list users, so they can't contain one
 pub fn parse_user_id(buffer: Vec<u8>) -> Result<String, MyError> {
-    let mut cursor = io::Cursor::new(buffer);
+    let user_id = String::from_utf8(buffer).map_err(MyError::ParseError)?;
+
+    if user_id.is_empty() {
+        return Err(MyError::MalformedBody("empty user ID"));
+    }
+    if user_id.contains(';') {
+        return Err(MyError::MalformedBody("user ID contains a ';'"));
+    }
+
+    return Ok(user_id);
+}
 
-    let mut user_id_buf = vec![];
+// The channel name is used as the database file name, so only allow characters that are valid
+// in a Twitch channel name
+pub fn parse_channel_name(buffer: Vec<u8>) -> Result<String, MyError> {
+    let channel_name = String::from_utf8(buffer).map_err(MyError::ParseError)?;
 
-    cursor
-        .read_to_end(&mut user_id_buf)
-        .map_err(|e| MyError::IoError(e))?;
+    if channel_name.is_empty()
+        || !channel_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(MyError::ChannelNameError(channel_name));
+    }
+
+    return Ok(channel_name);
+}
+
+// Season IDs are names like "2018-07"
+pub fn parse_season_id(buffer: Vec<u8>) -> Result<String, MyError> {
+    let season_id = String::from_utf8(buffer).map_err(MyError::ParseError)?;
+
+    if season_id.is_empty()
+        || season_id.len() > u8::MAX as usize
+        || !season_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(MyError::SeasonIdError(season_id));
+    }
 
-    return String::from_utf8(user_id_buf.to_vec()).map_err(|e| MyError::ParseError(e));
+    return Ok(season_id);
 }
 
+// Each user ID is followed by a ';'. A body that doesn't end with a ';' is rejected, rather than
+// guessing where the last user ID ends, and so are empty user IDs
 pub fn parse_user_id_bulk(buffer: Vec<u8>) -> Result<Vec<String>, MyError> {
-    let buffer_size = buffer.len();
-    let mut cursor = io::Cursor::new(buffer);
+    let mut reader = BodyReader::new(buffer);
 
     let mut user_ids = Vec::new();
 
-    while (cursor.position() as usize) < buffer_size - 1 {
-        let mut user_id_buf = vec![];
-        cursor
-            .read_until(b';', &mut user_id_buf)
-            .map_err(|e| MyError::IoError(e))?;
-        user_ids.push(
-            String::from_utf8(user_id_buf[..user_id_buf.len() - 1].to_vec())
-                .map_err(|e| MyError::ParseError(e))?,
-        );
+    while !reader.is_empty() {
+        user_ids.push(reader.read_user_id()?);
     }
 
     return Ok(user_ids);
 }
+
+// Each edit is an 8 byte signed amount, followed by the user ID and a ';'
+pub fn parse_amount_bulk(buffer: Vec<u8>) -> Result<Vec<(String, i64)>, MyError> {
+    let mut reader = BodyReader::new(buffer);
+
+    let mut edits = Vec::new();
+
+    while !reader.is_empty() {
+        let amount = reader.read_u64()? as i64;
+        let user_id = reader.read_terminated_string()?;
+        edits.push((user_id, amount));
+    }
+
+    return Ok(edits);
+}
+
+// Reads the fields of a body in order
+pub struct BodyReader {
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl BodyReader {
+    pub fn new(buffer: Vec<u8>) -> BodyReader {
+        return BodyReader {
+            buffer,
+            position: 0,
+        };
+    }
+
+    fn take(&mut self, size: usize) -> Result<&[u8], MyError> {
+        if self.buffer.len() - self.position < size {
+            return Err(MyError::BufferError);
+        }
+
+        let start = self.position;
+        self.position += size;
+
+        return Ok(&self.buffer[start..self.position]);
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, MyError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, MyError> {
+        return Ok(buf_to_u32_unsafe(self.take(4)?));
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, MyError> {
+        return buf_to_u64(self.take(8)?);
+    }
+
+    // A string followed by a ';'
+    pub fn read_terminated_string(&mut self) -> Result<String, MyError> {
+        let size = match self.buffer[self.position..].iter().position(|b| *b == b';') {
+            None => return Err(MyError::BufferError),
+            Some(s) => s,
+        };
+        let buf = self.take(size)?.to_vec();
+        self.position += 1;
+
+        return String::from_utf8(buf).map_err(MyError::ParseError);
+    }
+
+    // A user ID followed by a ';'. The user ID can't be empty
+    pub fn read_user_id(&mut self) -> Result<String, MyError> {
+        let user_id = self.read_terminated_string()?;
+        if user_id.is_empty() {
+            return Err(MyError::MalformedBody("empty user ID"));
+        }
+
+        return Ok(user_id);
+    }
+
+    // Fails if anything is left after the last field
+    pub fn finish(&self) -> Result<(), MyError> {
+        if !self.is_empty() {
+            return Err(MyError::MalformedBody(
+                "unexpected bytes after the last field",
+            ));
+        }
+
+        return Ok(());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.position >= self.buffer.len();
+    }
+
+    // A string prefixed with its 1 byte size
+    pub fn read_string(&mut self) -> Result<String, MyError> {
+        let size = self.read_u8()? as usize;
+        let buf = self.take(size)?.to_vec();
+
+        return String::from_utf8(buf).map_err(MyError::ParseError);
+    }
+
+    // Everything that has not been read yet
+    pub fn rest(self) -> Vec<u8> {
+        return self.buffer[self.position..].to_vec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_id() {
+        assert_eq!(
+            parse_user_id(b"alice".to_vec()).ok(),
+            Some("alice".to_string())
+        );
+        assert!(parse_user_id(Vec::new()).is_err());
+        assert!(parse_user_id(b"c;x".to_vec()).is_err());
+        assert!(parse_user_id(b"x;".to_vec()).is_err());
+        assert!(parse_user_id(b"\xff".to_vec()).is_err());
+    }
+
+    fn user_ids(buffer: &[u8]) -> Result<Vec<String>, MyError> {
+        return parse_user_id_bulk(buffer.to_vec());
+    }
+
+    #[test]
+    fn user_id_bulk() {
+        assert_eq!(
+            user_ids(b"alice;bob;").ok(),
+            Some(vec!["alice".to_string(), "bob".to_string()])
+        );
+        assert_eq!(
+            user_ids(b"a;b;").ok(),
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(user_ids(b"").ok(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn user_id_bulk_needs_trailing_separator() {
+        assert!(user_ids(b"alice;bob").is_err());
+        assert!(user_ids(b"a;b").is_err());
+        assert!(user_ids(b"alice").is_err());
+    }
+
+    #[test]
+    fn user_id_bulk_rejects_empty_user_ids() {
+        assert!(user_ids(b";").is_err());
+        assert!(user_ids(b"a;;b;").is_err());
+    }
+
+    #[test]
+    fn user_id_bulk_bad_utf8() {
+        assert!(user_ids(b"\xff;").is_err());
+    }
+
+    #[test]
+    fn body_reader_numbers() {
+        let mut reader = BodyReader::new(vec![7, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 9, 9]);
+
+        assert_eq!(reader.read_u8().ok(), Some(7));
+        assert_eq!(reader.read_u32().ok(), Some(256));
+        assert_eq!(reader.read_u64().ok(), Some(2));
+        assert!(reader.read_u32().is_err());
+        // A failed read doesn't move the reader
+        assert_eq!(reader.rest(), vec![9, 9]);
+    }
+
+    #[test]
+    fn body_reader_strings() {
+        let mut reader = BodyReader::new(b"\x03abcfrom;rest".to_vec());
+
+        assert_eq!(reader.read_string().ok(), Some("abc".to_string()));
+        assert_eq!(
+            reader.read_terminated_string().ok(),
+            Some("from".to_string())
+        );
+        assert!(reader.read_terminated_string().is_err());
+        assert!(!reader.is_empty());
+        assert_eq!(reader.rest(), b"rest".to_vec());
+    }
+
+    #[test]
+    fn body_reader_short_string() {
+        let mut reader = BodyReader::new(b"\x05abc".to_vec());
+
+        assert!(reader.read_string().is_err());
+    }
+
+    #[test]
+    fn body_reader_user_ids() {
+        let mut reader = BodyReader::new(b"alice;bob".to_vec());
+        assert_eq!(reader.read_user_id().ok(), Some("alice".to_string()));
+        // The last user ID is not cut short
+        assert!(reader.read_user_id().is_err());
+
+        let mut reader = BodyReader::new(b";".to_vec());
+        assert!(reader.read_user_id().is_err());
+
+        let mut reader = BodyReader::new(b"a;".to_vec());
+        assert_eq!(reader.read_user_id().ok(), Some("a".to_string()));
+        assert!(reader.finish().is_ok());
+    }
+
+    #[test]
+    fn body_reader_finish() {
+        let mut reader = BodyReader::new(b"from;to;x".to_vec());
+
+        assert_eq!(reader.read_user_id().ok(), Some("from".to_string()));
+        assert_eq!(reader.read_user_id().ok(), Some("to".to_string()));
+        assert!(reader.finish().is_err());
+    }
+
+    #[test]
+    fn amount_bulk() {
+        let mut buffer = u64_to_buf(5).to_vec();
+        buffer.extend_from_slice(b"alice;");
+        buffer.extend_from_slice(&u64_to_buf(-3i64 as u64));
+        buffer.extend_from_slice(b"bob;");
+
+        let edits = parse_amount_bulk(buffer.clone()).ok();
+        assert_eq!(
+            edits,
+            Some(vec![("alice".to_string(), 5), ("bob".to_string(), -3)])
+        );
+
+        // The last user ID is not cut short
+        buffer.pop();
+        assert!(parse_amount_bulk(buffer).is_err());
+    }
+}
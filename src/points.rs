@@ -1,16 +1,34 @@
 use chrono::prelude::*;
 
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
 use std::path::Path;
-use std::{io, thread};
+use std::{io, slice, thread};
 
 use std::sync::mpsc::{channel, Receiver, Sender};
 
-use client::Command;
+use client::{
+    BulkEditSummary, ChannelFailure, ChannelResults, CloseSeason, Command, Delete, DeletedUser,
+    LeaderboardEntry, Merge, MergedUser, Operation, ResponseSender,
+};
+use common::MyError;
+use format::{self, FormatError};
+use journal::{Change, Journal};
+use ranks::RankIndex;
+use season::Season;
+use utils::*;
 
-use bincode::{deserialize, serialize};
+// Added to the name of a database that could not be read, followed by a timestamp
+const QUARANTINE_SUFFIX: &str = ".corrupt-";
+
+// Added to the name of a copy of a database made before the channel was reset, followed by a
+// timestamp
+const ARCHIVE_SUFFIX: &str = ".archive-";
+
+// Added to the name of a database to get the file a closed season is stored in, followed by the
+// season ID
+const SEASON_SUFFIX: &str = ".season-";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChannelPoints {
@@ -18,27 +36,61 @@ pub struct ChannelPoints {
     path: String,
 
     // Key = User ID
-    // Value = Rank
-    user_id_to_rank: HashMap<String, u64>,
+    // Value = Points
+    #[serde(skip_deserializing, skip_serializing)]
+    user_id_to_points: HashMap<String, u64>,
 
-    // Sorted vector of points and User IDs
-    ranks: Vec<(u64, String)>,
+    // Points and User IDs, sorted by points
+    ranks: RankIndex,
 
+    // Changes since the database was last saved
     #[serde(skip_deserializing, skip_serializing)]
-    receiver: Option<Receiver<Command>>,
+    journal: Option<Journal>,
+
+    // Changes made by the command currently being handled, not yet written to the journal
+    #[serde(skip_deserializing, skip_serializing)]
+    pending_changes: Vec<Change>,
 }
 
 impl ChannelPoints {
     pub fn new(path: &str) -> ChannelPoints {
         return ChannelPoints {
             path: path.to_string(),
-            user_id_to_rank: HashMap::new(),
-            ranks: Vec::new(),
-            receiver: None,
+            user_id_to_points: HashMap::new(),
+            ranks: RankIndex::new(),
+            journal: None,
+            pending_changes: Vec::new(),
         };
     }
 
+    pub fn from_ranks(ranks: RankIndex) -> ChannelPoints {
+        let mut c = ChannelPoints::new("");
+        c.ranks = ranks;
+
+        return c;
+    }
+
+    // Loads the last saved database, and replays the changes made since then from the journal
     pub fn load(path: &str) -> io::Result<ChannelPoints> {
+        let mut c = ChannelPoints::load_database(path)?;
+
+        let (journal, records) = Journal::open(&format!("{}.journal", path))?;
+        if !records.is_empty() {
+            info!(
+                "Replaying {} records from {}",
+                records.len(),
+                journal.path()
+            );
+        }
+        for change in records.iter().flatten() {
+            c.apply(change);
+        }
+        c.journal = Some(journal);
+
+        return Ok(c);
+    }
+
+    fn load_database(path: &str) -> io::Result<ChannelPoints> {
         match File::open(path) {
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
                 return Ok(ChannelPoints::new(path));
@@ -50,11 +102,29 @@ impl ChannelPoints {
                 let mut buf = Vec::new();
                 file.read_to_end(&mut buf)?;
 
-                match deserialize(&buf) {
-                    Err(_) => {
-                        return Ok(ChannelPoints::new(path));
+                match format::decode(&buf) {
+                    Err(FormatError::UnknownVersion(v)) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} has unknown format version {}", path, v),
+                        ));
+                    }
+                    Err(FormatError::Corrupt(e)) => {
+                        // Never replace a database we can't read, it might still be recoverable
+                        let quarantine_path = quarantine(path)?;
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{} is corrupt ({}), moved to {}", path, e, quarantine_path),
+                        ));
                     }
-                    Ok(m) => {
+                    Ok((mut m, version, seasons)) => {
+                        m.path = path.to_string();
+                        m.rebuild_user_map();
+
+                        if version < format::CURRENT_VERSION {
+                            m.migrate(version, seasons)?;
+                        }
+
                         return Ok(m);
                     }
                 }
@@ -62,80 +132,490 @@ impl ChannelPoints {
         }
     }
 
+    // Rewrites a database that was loaded from an older format version in the current version,
+    // moving the seasons it held to files of their own.
+    // The old file is kept next to it, in case the migration needs to be undone
+    fn migrate(&self, from_version: u32, seasons: BTreeMap<String, Season>) -> io::Result<()> {
+        fs::copy(&self.path, format!("{}.v{}", self.path, from_version))?;
+
+        // The seasons are only dropped from the database once they are safely in their files
+        for (season_id, season) in seasons {
+            let season_path = self.season_path(&season_id);
+            if !Path::new(&season_path).exists() {
+                write_season(&season_path, &season)?;
+            }
+        }
+
+        self.save()?;
+
+        info!(
+            "Migrated {} from format version {} to {}",
+            self.path,
+            from_version,
+            format::CURRENT_VERSION
+        );
+
+        return Ok(());
+    }
+
     pub fn save(&self) -> io::Result<()> {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(self.path.clone())?;
+        self.write_database(&self.path)?;
 
-        let mut buf = serialize(&self).unwrap();
-        file.write(&mut buf)?;
+        // Everything in the journal is part of the saved database now
+        if let Some(ref journal) = self.journal {
+            journal.clear()?;
+        }
 
         return Ok(());
     }
 
-    fn edit_points(&mut self, user_id: String, points: i32) -> u64 {
+    fn write_database(&self, path: &str) -> io::Result<()> {
+        let buf =
+            format::encode(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        return write_atomically(path, &buf);
+    }
+
+    // Writes a copy of the points next to the database. Returns the path of the copy
+    fn archive(&self) -> io::Result<String> {
+        let archive_path = timestamped_path(&self.path, ARCHIVE_SUFFIX);
+        self.write_database(&archive_path)?;
+
+        return Ok(archive_path);
+    }
+
+    // Archives the points, and removes every user once the archive is safely on disk.
+    // Returns the file name of the archive
+    fn reset(&mut self) -> Result<String, MyError> {
+        let archive_path = self.archive().map_err(|e| {
+            MyError::InternalError(format!("unable to archive {}: {}", self.path, e))
+        })?;
+
+        let change = Change::Clear;
+        self.apply(&change);
+        self.pending_changes.push(change);
+
+        info!("Reset {}, archived to {}", self.path, archive_path);
+
+        // Clients don't need to know where the database directory is
+        let file_name = Path::new(&archive_path)
+            .file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or(&archive_path);
+
+        return Ok(file_name.to_string());
+    }
+
+    fn save_and_log(&self) {
+        let start = Utc::now();
+        match self.save() {
+            Err(e) => {
+                error!("Error saving {}: {}", self.path, e);
+            }
+            Ok(_) => {
+                let end = Utc::now();
+                info!("Saving {} took {}", self.path, end - start);
+            }
+        }
+    }
+
+    // The rank index is the source of truth, the user map is only a lookup table into it
+    fn rebuild_user_map(&mut self) {
+        self.user_id_to_points = self
+            .ranks
+            .iter()
+            .map(|(points, user_id)| (user_id.to_string(), points))
+            .collect();
+    }
+
+    fn edit_points(&mut self, user_id: String, points: i64) -> u64 {
         if points > 0 {
             return self.add_points(user_id, points as u64);
         } else if points < 0 {
-            return self.remove_points(user_id, points.abs() as u64);
+            return self.remove_points(user_id, points.unsigned_abs());
         }
 
-        return self.get_points(user_id);
+        return self.get_points(&user_id);
+    }
+
+    fn bulk_edit(&mut self, edits: Vec<(String, i64)>) -> BulkEditSummary {
+        let mut summary = BulkEditSummary::default();
+
+        for (user_id, points) in edits {
+            if points == 0 || user_id.is_empty() {
+                summary.skipped += 1;
+                continue;
+            }
+
+            let old_points = self.get_points(&user_id);
+            let new_points = self.edit_points(user_id, points);
+
+            if new_points.abs_diff(old_points) == points.unsigned_abs() {
+                summary.applied += 1;
+            } else {
+                summary.clamped += 1;
+            }
+        }
+
+        return summary;
     }
 
     fn add_points(&mut self, user_id: String, points: u64) -> u64 {
-        let user_rank = self.user_id_to_rank.entry(user_id).or_insert(0);
+        let user_points = self.get_points(&user_id).saturating_add(points);
+
+        return self.set_points(user_id, user_points);
+    }
 
-        // TODO: Handle rank 0
+    fn remove_points(&mut self, user_id: String, points: u64) -> u64 {
+        let user_points = self.get_points(&user_id).saturating_sub(points);
 
-        let mut res = self.ranks.get_mut(*user_rank as usize);
-        match res {
-            None => {
-                //
+        return self.set_points(user_id, user_points);
+    }
+
+    // Moves points from one user to another. Returns false, and moves nothing, if the sender does
+    // not have enough points. Fails, and moves nothing, if the receiver would overflow
+    fn transfer_points(
+        &mut self,
+        from_user_id: String,
+        to_user_id: String,
+        amount: u64,
+    ) -> Result<bool, MyError> {
+        let from_points = self.get_points(&from_user_id);
+        if from_points < amount {
+            return Ok(false);
+        }
+
+        if from_user_id == to_user_id {
+            return Ok(true);
+        }
+
+        let to_points = match self.get_points(&to_user_id).checked_add(amount) {
+            None => return Err(MyError::PointsOverflow(to_user_id)),
+            Some(p) => p,
+        };
+
+        self.set_points(from_user_id, from_points - amount);
+        self.set_points(to_user_id, to_points);
+
+        return Ok(true);
+    }
+
+    fn set_points(&mut self, user_id: String, points: u64) -> u64 {
+        match self.user_id_to_points.get(&user_id) {
+            Some(p) if *p == points => return points,
+            // Users without points are only added to the index once they get some
+            None if points == 0 => return points,
+            _ => {}
+        }
+
+        let change = Change::Set(user_id, points);
+        self.apply(&change);
+        self.pending_changes.push(change);
+
+        return points;
+    }
+
+    // Removes the user from the channel. Returns the points the user had, or None if the user
+    // did not exist
+    fn delete_user(&mut self, user_id: String) -> Option<u64> {
+        let points = *self.user_id_to_points.get(&user_id)?;
+
+        let change = Change::Remove(user_id);
+        self.apply(&change);
+        self.pending_changes.push(change);
+
+        return Some(points);
+    }
+
+    // Removes the users from every closed season of the channel, rewriting the seasons they were
+    // in. Returns the users that were in any season
+    fn delete_from_seasons(&self, user_ids: &[String]) -> Result<HashSet<String>, MyError> {
+        let mut in_seasons = HashSet::new();
+
+        for season_id in self.season_ids()? {
+            let mut season = self.load_season(&season_id)?;
+
+            let mut removed = false;
+            for user_id in user_ids {
+                if season.remove(user_id) {
+                    in_seasons.insert(user_id.clone());
+                    removed = true;
+                }
             }
-            Some((user_points, _)) => {
-                *user_points += points;
-                // TODO(pajlada): RECALCULATE RANK
-                return *user_points;
+
+            if removed {
+                write_season(&self.season_path(&season_id), &season).map_err(|e| {
+                    MyError::InternalError(format!("unable to write season {}: {}", season_id, e))
+                })?;
             }
         }
 
-        return 0;
+        return Ok(in_seasons);
     }
 
-    fn remove_points(&self, user_id: String, points: u64) -> u64 {
-        /*
-        let user_points = channel_points.entry(user_id).or_insert(0);
+    // Adds the points of one user to another, and deletes the first user.
+    // Returns the points that were moved and the new points of the second user, or None if there
+    // was nothing to merge
+    fn merge_users(
+        &mut self,
+        from_user_id: String,
+        to_user_id: String,
+    ) -> Result<Option<(u64, u64)>, MyError> {
+        if from_user_id == to_user_id {
+            return Ok(None);
+        }
 
-        if points > *user_points {
-            *user_points = 0;
-        } else {
-            *user_points -= points;
+        let points = match self.user_id_to_points.get(&from_user_id) {
+            None => return Ok(None),
+            Some(p) => *p,
+        };
+
+        // Nothing is changed if the points don't fit, so no points are lost
+        let new_points = match self.get_points(&to_user_id).checked_add(points) {
+            None => {
+                warn!(
+                    "Not merging {} into {} in {}, the points would overflow",
+                    from_user_id, to_user_id, self.path
+                );
+                return Err(MyError::PointsOverflow(to_user_id));
+            }
+            Some(p) => p,
+        };
+
+        self.delete_user(from_user_id);
+        self.set_points(to_user_id, new_points);
+
+        return Ok(Some((points, new_points)));
+    }
+
+    fn apply(&mut self, change: &Change) {
+        match change {
+            Change::Set(user_id, points) => {
+                // Move the user to their new place in the rank index
+                if let Some(old_points) = self.user_id_to_points.insert(user_id.clone(), *points) {
+                    self.ranks.remove(old_points, user_id);
+                }
+                self.ranks.insert(*points, user_id.clone());
+            }
+            Change::Remove(user_id) => {
+                if let Some(points) = self.user_id_to_points.remove(user_id) {
+                    self.ranks.remove(points, user_id);
+                }
+            }
+            Change::Clear => {
+                self.user_id_to_points.clear();
+                self.ranks = RankIndex::new();
+            }
+            Change::RemoveFromSeasons(user_id) => {
+                if let Err(e) = self.delete_from_seasons(slice::from_ref(user_id)) {
+                    error!(
+                        "Error deleting {} from the seasons of {}: {}",
+                        user_id, self.path, e
+                    );
+                }
+            }
+            Change::CloseSeason(season_id) => {
+                // A season that is already closed was written with the ranks it was closed with,
+                // which the current ranks may have moved on from since
+                let season_path = self.season_path(season_id);
+                if !Path::new(&season_path).exists() {
+                    let season = Season::new(self.ranks.clone());
+                    if let Err(e) = write_season(&season_path, &season) {
+                        error!("Error writing season {}: {}", season_path, e);
+                    }
+                }
+            }
         }
+    }
 
-        return *user_points;
-        */
-        return 420;
+    // Writes the changes made by the current command to the journal as one record.
+    // Must be called before the command is answered, and the command must fail if this fails
+    fn write_journal(&mut self) -> Result<(), MyError> {
+        if self.pending_changes.is_empty() {
+            return Ok(());
+        }
+
+        let result = match self.journal {
+            None => Ok(()),
+            Some(ref journal) => journal.append(&self.pending_changes),
+        };
+        self.pending_changes.clear();
+
+        if let Err(e) = result {
+            error!(
+                "Error writing to the journal of {}: {}. Saving the database instead",
+                self.path, e
+            );
+
+            // The changes are made, so they are only safe once they are part of a saved database
+            if let Err(e) = self.save() {
+                error!("Error saving {}: {}", self.path, e);
+                return Err(MyError::InternalError(format!(
+                    "the change was made, but could not be written to disk: {}",
+                    e
+                )));
+            }
+        }
+
+        return Ok(());
     }
 
-    fn get_points(&self, user_id: String) -> u64 {
-        let user_rank = match self.user_id_to_rank.get(&user_id) {
+    fn get_points(&self, user_id: &str) -> u64 {
+        match self.user_id_to_points.get(user_id) {
             None => {
                 // User did not exist in the points database
                 return 0;
             }
-            Some(rank) => rank,
+            Some(points) => {
+                return *points;
+            }
+        }
+    }
+
+    // Rank 1 is the user with the most points. Users with the same amount of points share a rank
+    fn get_rank(&self, user_id: &str) -> u64 {
+        let user_points = self.get_points(user_id);
+
+        return self.ranks.count_above(user_points) as u64 + 1;
+    }
+
+    fn leaderboard(&self, offset: usize, limit: usize) -> Vec<LeaderboardEntry> {
+        return leaderboard(&self.ranks, offset, limit);
+    }
+
+    // The user, along with up to `count` users ranked right above and below them
+    fn around(&self, user_id: String, count: usize) -> Vec<LeaderboardEntry> {
+        let points = self.get_points(&user_id);
+        let position = self.ranks.count_before(points, &user_id);
+        let start = position.saturating_sub(count);
+
+        if self.user_id_to_points.contains_key(&user_id) {
+            return self.leaderboard(start, position - start + 1 + count);
+        }
+
+        // Users without points are not in the index, show them where they would be
+        let rank = self.get_rank(&user_id);
+        let mut entries = self.leaderboard(start, position - start + count);
+        entries.insert(
+            position - start,
+            LeaderboardEntry {
+                user_id,
+                points,
+                rank,
+            },
+        );
+
+        return entries;
+    }
+
+    // Writes the current ranks to the file of the season, and removes every user if asked to.
+    // Returns the number of users in the season
+    fn close_season(&mut self, c: &CloseSeason) -> Result<u64, MyError> {
+        let season_path = self.season_path(&c.season_id);
+        if Path::new(&season_path).exists() {
+            return Err(MyError::SeasonExists(c.season_id.clone()));
+        }
+
+        let users = self.ranks.len() as u64;
+
+        // The season is safely on disk before any user is removed
+        write_season(&season_path, &Season::new(self.ranks.clone())).map_err(|e| {
+            MyError::InternalError(format!("unable to write season {}: {}", c.season_id, e))
+        })?;
+
+        if c.reset {
+            let change = Change::Clear;
+            self.apply(&change);
+            self.pending_changes.push(change);
+        }
+
+        info!(
+            "Closed season {} of {} with {} users",
+            c.season_id, self.path, users
+        );
+
+        return Ok(users);
+    }
+
+    fn season_path(&self, season_id: &str) -> String {
+        return format!("{}{}{}", self.path, SEASON_SUFFIX, season_id);
+    }
+
+    // IDs of every closed season of the channel
+    fn season_ids(&self) -> Result<Vec<String>, MyError> {
+        let path = Path::new(&self.path);
+        let prefix = match path.file_name().and_then(|f| f.to_str()) {
+            None => return Ok(Vec::new()),
+            Some(f) => format!("{}{}", f, SEASON_SUFFIX),
         };
 
-        let user_points = match self.ranks.get(*user_rank as usize) {
-            None => {
-                return 0;
+        let entries = fs::read_dir(parent_directory(path)).map_err(|e| {
+            MyError::InternalError(format!(
+                "unable to list the seasons of {}: {}",
+                self.path, e
+            ))
+        })?;
+
+        let mut season_ids = Vec::new();
+        for entry in entries.flatten() {
+            if let Ok(file_name) = entry.file_name().into_string() {
+                // Season IDs can't contain dots, so this skips temporary files
+                if file_name.starts_with(&prefix) && !file_name[prefix.len()..].contains('.') {
+                    season_ids.push(file_name[prefix.len()..].to_string());
+                }
             }
-            Some((points, _)) => points,
+        }
+
+        return Ok(season_ids);
+    }
+
+    fn load_season(&self, season_id: &str) -> Result<Season, MyError> {
+        let season_path = self.season_path(season_id);
+
+        let buf = match fs::read(&season_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(MyError::UnknownSeason(season_id.to_string()));
+            }
+            Err(e) => {
+                return Err(MyError::InternalError(format!(
+                    "unable to read {}: {}",
+                    season_path, e
+                )));
+            }
+            Ok(b) => b,
+        };
+
+        return format::decode_season(&buf)
+            .map_err(|e| MyError::InternalError(format!("unable to read {}: {}", season_path, e)));
+    }
+
+    fn season_leaderboard(
+        &self,
+        season_id: &str,
+        limit: usize,
+    ) -> Result<Vec<LeaderboardEntry>, MyError> {
+        let season = self.load_season(season_id)?;
+
+        return Ok(leaderboard(season.ranks(), 0, limit));
+    }
+
+    // Returns None if the user was not in the season
+    fn season_rank(
+        &self,
+        season_id: &str,
+        user_id: String,
+    ) -> Result<Option<LeaderboardEntry>, MyError> {
+        let season = self.load_season(season_id)?;
+        let points = match season.get_points(&user_id) {
+            None => return Ok(None),
+            Some(p) => p,
         };
 
-        return *user_points;
+        return Ok(Some(LeaderboardEntry {
+            user_id,
+            points,
+            rank: season.ranks().count_above(points) as u64 + 1,
+        }));
     }
 
     pub fn listen(mut self, r: Receiver<Command>) {
@@ -146,97 +626,153 @@ impl ChannelPoints {
                     // ...
                 }
                 Ok(cmd) => match cmd {
+                    Connect(_) => {
+                        // Channels are created by Points, nothing left to do once we are listening
+                    }
                     GetPoints(c) => {
-                        match self.user_id_to_rank.get(&c.channel_name) {
-                            None => {
-                                // User did not exist in the points database
-                                c.response_sender.send(0).unwrap();
-                            }
-                            Some(rank) => {
-                                // TODO(pajlada): Get points from rank index
-                                c.response_sender.send(1337).unwrap();
-                            }
-                        }
+                        let user_points = self.get_points(&c.user_id);
+                        c.response_sender.send(Ok(user_points));
+                    }
+                    GetPointsBulk(c) => {
+                        let users = c
+                            .user_ids
+                            .iter()
+                            .map(|user_id| (self.get_points(user_id), self.get_rank(user_id)))
+                            .collect();
+                        c.response_sender.send(Ok(users));
                     }
                     BulkEdit(c) => {
-                        for user_id in c.user_ids {
-                            self.edit_points(user_id, c.points);
+                        let summary = self.bulk_edit(c.edits);
+                        let journaled = self.write_journal();
+                        if let Some(response_sender) = c.response_sender {
+                            response_sender.send(journaled.and(Ok(summary)));
                         }
                     }
                     Edit(c) => {
-                        // points.forward(c.channel_name, cmd);
-                            /*
-                        let channel_points = points
-                            .entry(c.channel_name)
-                            .or_insert(ChannelPointMap::new());
+                        let old_value = self.get_points(&c.user_id);
 
                         match c.operation {
                             Operation::Add => {
-                                let new_value = add_points(channel_points, c.user_id, c.value);
-                                c.response_sender.send((true, new_value)).unwrap();
+                                let new_value = self.add_points(c.user_id, c.value);
+                                let journaled = self.write_journal();
+                                c.response_sender
+                                    .send(journaled.and(Ok((true, old_value, new_value))));
                             }
                             Operation::Remove => {
-                                if !c.force {
-                                    let user_value = get_points(channel_points, c.user_id.clone());
-
-                                    if user_value < c.value {
-                                        c.response_sender.send((false, user_value)).unwrap();
-                                        continue;
-                                    }
+                                if !c.force && old_value < c.value {
+                                    c.response_sender.send(Ok((false, old_value, old_value)));
+                                    continue;
                                 }
 
-                                let new_value = remove_points(channel_points, c.user_id, c.value);
-                                c.response_sender.send((true, new_value)).unwrap();
+                                // A forced remove takes as many points as the user has
+                                let new_value = self.remove_points(c.user_id, c.value);
+                                let journaled = self.write_journal();
+                                c.response_sender
+                                    .send(journaled.and(Ok((true, old_value, new_value))));
+                            }
+                            Operation::Set => {
+                                let new_value = self.set_points(c.user_id, c.value);
+                                let journaled = self.write_journal();
+                                c.response_sender
+                                    .send(journaled.and(Ok((true, old_value, new_value))));
                             }
                         }
-                        */
                     }
                     Rank(c) => {
-                        // points.forward(c.channel_name, cmd);
-                            /*
-                        let channel_points = points
-                            .entry(c.channel_name)
-                            .or_insert(ChannelPointMap::new());
-
-                        let user_points = get_points(channel_points, c.user_id.clone());
-                        let mut points: Vec<&u64> = Vec::new();
-                        for (_other_user_id, other_user_points) in channel_points {
-                            points.push(other_user_points);
-                        }
-                        points.sort();
-
-                        let mut rank = points.len() as u64;
-                        for other_user_points in points {
-                            if other_user_points < &user_points {
-                                rank -= 1;
-                            } else {
-                                break;
-                            }
-                        }
+                        let user_rank = self.get_rank(&c.user_id);
+                        c.response_sender.send(Ok(user_rank));
+                    }
+                    Transfer(c) => {
+                        let result = self.transfer_points(
+                            c.from_user_id.clone(),
+                            c.to_user_id.clone(),
+                            c.amount,
+                        );
+                        // Both users are in the same journal record
+                        let journaled = self.write_journal();
 
-                        println!("Responding with rank {}", rank);
+                        let from_points = self.get_points(&c.from_user_id);
+                        let to_points = self.get_points(&c.to_user_id);
+                        c.response_sender.send(
+                            journaled
+                                .and(result)
+                                .map(|moved| (moved, from_points, to_points)),
+                        );
+                    }
+                    Delete(c) => {
+                        // Deleted users don't stay readable through the seasons they were in
+                        let in_seasons = self.delete_from_seasons(&c.user_ids);
 
-                        c.response_sender.send(rank).unwrap();
-                        */
+                        let mut deleted = Vec::new();
+                        for user_id in c.user_ids {
+                            let points = self.delete_user(user_id.clone());
+                            let in_a_season = match in_seasons {
+                                Err(_) => false,
+                                Ok(ref s) => s.contains(&user_id),
+                            };
+                            if points.is_some() || in_a_season {
+                                deleted.push(DeletedUser {
+                                    channel_name: c.channel_name.clone(),
+                                    user_id,
+                                    points: points.unwrap_or(0),
+                                });
+                            }
+                        }
+                        let journaled = self.write_journal();
+                        c.response_sender.send(
+                            journaled
+                                .and(in_seasons)
+                                .map(|_| ChannelResults::new(deleted)),
+                        );
+                    }
+                    Merge(c) => {
+                        let result = match self.merge_users(c.from_user_id, c.to_user_id) {
+                            Err(e) => Err(e),
+                            Ok(None) => Ok(Vec::new()),
+                            Ok(Some((points, new_points))) => Ok(vec![MergedUser {
+                                channel_name: c.channel_name,
+                                points,
+                                new_points,
+                            }]),
+                        };
+                        // Both users are in the same journal record
+                        let journaled = self.write_journal();
+                        c.response_sender
+                            .send(journaled.and(result).map(ChannelResults::new));
                     }
-                    SavePoints => {
-                        /*
-                        let utc: DateTime<Utc> = Utc::now(); // e.g. `2014-11-28T12:45:59.324310806Z`
-                        save_points(&mut points).unwrap();
-                        let utc2 = Utc::now();
-                        println!("saving took {}", utc2 - utc);
-                        */
-                        println!("unimplemented");
+                    Reset(c) => {
+                        let result = self.reset();
+                        let journaled = self.write_journal();
+                        c.response_sender.send(journaled.and(result));
+                    }
+                    CloseSeason(c) => {
+                        let result = self.close_season(&c);
+                        let journaled = self.write_journal();
+                        c.response_sender.send(journaled.and(result));
+                    }
+                    SeasonLeaderboard(c) => {
+                        let result = self.season_leaderboard(&c.season_id, c.limit);
+                        c.response_sender.send(result);
+                    }
+                    SeasonRank(c) => {
+                        let result = self.season_rank(&c.season_id, c.user_id);
+                        c.response_sender.send(result);
+                    }
+                    Leaderboard(c) => {
+                        let entries = self.leaderboard(c.offset, c.limit);
+                        c.response_sender.send(Ok(entries));
+                    }
+                    Around(c) => {
+                        let entries = self.around(c.user_id, c.count);
+                        c.response_sender.send(Ok(entries));
+                    }
+                    SavePoints(sender) => {
+                        self.save_and_log();
+                        sender.send(()).unwrap();
                     }
                     Quit(sender) => {
-                        /*
-                        let utc: DateTime<Utc> = Utc::now(); // e.g. `2014-11-28T12:45:59.324310806Z`
-                        save_points(&mut points).unwrap();
-                        let utc2 = Utc::now();
-                        println!("saving took {}", utc2 - utc);
+                        self.save_and_log();
                         sender.send(()).unwrap();
-                        */
-                        println!("unimplemented");
                         break;
                     }
                 },
@@ -247,72 +783,718 @@ impl ChannelPoints {
 
 #[derive(Debug)]
 pub struct Points {
+    // Directory the channel databases are stored in
+    path: String,
+
     pub channels: HashMap<String, Sender<Command>>,
+
+    // Channels whose database could not be loaded. These are not served until the database has
+    // been fixed by hand, so it doesn't get replaced by an empty one
+    refused_channels: HashSet<String>,
 }
 
 impl Points {
-    fn new() -> Points {
+    fn new(path: &str) -> Points {
         return Points {
+            path: path.to_string(),
             channels: HashMap::new(),
+            refused_channels: HashSet::new(),
         };
     }
 
     fn load_channels(directory: &str) -> io::Result<Points> {
-        let mut p = Points::new();
+        let mut p = Points::new(directory);
 
         let db_folder = Path::new(directory);
-        for db_file in db_folder.read_dir()? {
-            if let Ok(entry) = db_file {
-                if let Some(path_str) = entry.path().to_str() {
-                    let mut c = ChannelPoints::load(path_str)?;
-                    if let Ok(a) = entry.file_name().into_string() {
-                        let (sender, receiver) = channel();
-                        thread::spawn(move || listen_on_channel(c, receiver));
-                        // channels only needs to contain the channel to be able to communicate
-                        // with c
-                        p.channels.insert(a, sender);
-                    }
-                }
+        fs::create_dir_all(db_folder)?;
+        let mut quarantined_channels = Vec::new();
+
+        for entry in db_folder.read_dir()?.flatten() {
+            let file_name = match entry.file_name().into_string() {
+                Err(_) => continue,
+                Ok(f) => f,
+            };
+
+            if let Some(index) = file_name.find(QUARANTINE_SUFFIX) {
+                quarantined_channels.push(file_name[..index].to_string());
+                continue;
+            }
+
+            // Channel names can't contain dots, so any file with one is not a channel database.
+            // This skips temporary files left behind by an interrupted save
+            let is_channel_file = match entry.file_type() {
+                Err(_) => false,
+                Ok(t) => t.is_file() && !file_name.contains('.'),
+            };
+            if !is_channel_file {
+                continue;
+            }
+
+            if let Some(path_str) = entry.path().to_str() {
+                p.load_channel(file_name, path_str)?;
+            }
+        }
+
+        // A quarantined channel without a new database is still broken from an earlier start
+        for channel_name in quarantined_channels {
+            if !p.channels.contains_key(&channel_name)
+                && !p.refused_channels.contains(&channel_name)
+            {
+                error!(
+                    "Database of channel {} is quarantined. Refusing to serve the channel \
+                     until it is restored",
+                    channel_name
+                );
+                p.refused_channels.insert(channel_name);
             }
         }
 
         return Ok(p);
     }
 
+    fn load_channel(&mut self, channel_name: String, path: &str) -> io::Result<()> {
+        match ChannelPoints::load(path) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                self.refuse_channel(channel_name, e);
+            }
+            Err(e) => {
+                return Err(e);
+            }
+            Ok(c) => {
+                let (sender, receiver) = channel();
+                thread::spawn(move || listen_on_channel(c, receiver));
+                // channels only needs to contain the channel to be able to communicate with c
+                self.channels.insert(channel_name, sender);
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn refuse_channel(&mut self, channel_name: String, e: &io::Error) {
+        error!(
+            "Unable to load database of channel {}: {}. Refusing to serve the channel \
+             until it is restored",
+            channel_name, e
+        );
+        self.refused_channels.insert(channel_name);
+    }
+
     pub fn load(path: &str) -> io::Result<Points> {
         let start = Utc::now();
         match Points::load_channels(path) {
             Err(e) => {
                 let end = Utc::now();
-                println!("Error loading points. Took {}", end - start);
+                error!("Error loading points. Took {}", end - start);
                 return Err(e);
             }
             Ok(p) => {
                 let end = Utc::now();
-                println!("Loading points took {}", end - start);
+                info!("Loading points took {}", end - start);
                 return Ok(p);
             }
         }
     }
 
-    pub fn forward(&self, channel_name: String, command: Command) {
-        for (channel_name, _) in &self.channels {
-            println!("Found channel; {}", channel_name);
+    // Sends a command to every channel, and waits until all of them have acknowledged it
+    fn broadcast(&self, command: fn(Sender<()>) -> Command) {
+        wait_for_all(self.send_to_all(command));
+    }
+
+    // Sends a command to every channel. Returns the receivers the channels acknowledge it on
+    fn send_to_all(&self, command: fn(Sender<()>) -> Command) -> Vec<Receiver<()>> {
+        let mut receivers = Vec::new();
+        for (channel_name, channel_sender) in &self.channels {
+            let (sender, receiver) = channel();
+            match channel_sender.send(command(sender)) {
+                Err(_) => {
+                    warn!("Channel {} is no longer listening", channel_name);
+                }
+                Ok(_) => {
+                    receivers.push(receiver);
+                }
+            }
         }
 
+        return receivers;
+    }
+
+    // Saves every channel, and acknowledges on the sender once all of them are saved.
+    // The saves are waited for on another thread, so other commands don't have to wait for the
+    // slowest channel
+    pub fn save(&self, sender: Sender<()>) {
+        let start = Utc::now();
+        let receivers = self.send_to_all(Command::SavePoints);
+        let channel_count = receivers.len();
+
+        thread::spawn(move || {
+            wait_for_all(receivers);
+            let end = Utc::now();
+            info!("Saving {} channels took {}", channel_count, end - start);
+
+            sender.send(()).unwrap();
+        });
+    }
+
+    // Sends a copy of a command to every channel, and answers with the answers of every channel
+    // combined. The answers are collected on another thread, so other commands don't have to wait
+    fn forward_to_all<T, F>(&self, command: F, response_sender: ResponseSender<ChannelResults<T>>)
+    where
+        T: Send + 'static,
+        F: Fn(String, ResponseSender<ChannelResults<T>>) -> Command,
+    {
+        // The command still runs in every other channel, and the answer names the channels it
+        // could not run in, so a partial run isn't mistaken for a complete one
+        let mut combined = ChannelResults::new(Vec::new());
+        for channel_name in &self.refused_channels {
+            combined.failures.push(unknown_channel(channel_name));
+        }
+
+        let mut receivers = Vec::new();
+        for (channel_name, channel_sender) in &self.channels {
+            let (sender, receiver) = channel();
+            let channel_command = command(channel_name.clone(), ResponseSender::local(sender));
+            if channel_sender.send(channel_command).is_err() {
+                warn!("Channel {} is no longer listening", channel_name);
+                combined.failures.push(unknown_channel(channel_name));
+                continue;
+            }
+            receivers.push((channel_name.clone(), receiver));
+        }
+
+        thread::spawn(move || {
+            for (channel_name, receiver) in receivers {
+                match receiver.recv() {
+                    // The channel stopped without answering
+                    Err(_) => combined.failures.push(unknown_channel(&channel_name)),
+                    // The other channels have already made their changes, so their answers are
+                    // still sent
+                    Ok(Err(error)) => combined.failures.push(ChannelFailure {
+                        channel_name,
+                        error,
+                    }),
+                    Ok(Ok(mut answer)) => {
+                        combined.results.append(&mut answer.results);
+                        combined.failures.append(&mut answer.failures);
+                    }
+                }
+            }
+
+            combined
+                .failures
+                .sort_by(|a, b| a.channel_name.cmp(&b.channel_name));
+            response_sender.send(Ok(combined));
+        });
+    }
+
+    pub fn delete_from_all(&self, delete: Delete) {
+        let user_ids = delete.user_ids;
+        self.forward_to_all(
+            |channel_name, response_sender| {
+                Command::Delete(Delete {
+                    channel_name,
+                    user_ids: user_ids.clone(),
+                    all_channels: false,
+                    response_sender,
+                })
+            },
+            delete.response_sender,
+        );
+    }
+
+    pub fn merge_in_all(&self, merge: Merge) {
+        let from_user_id = merge.from_user_id;
+        let to_user_id = merge.to_user_id;
+        self.forward_to_all(
+            |channel_name, response_sender| {
+                Command::Merge(Merge {
+                    channel_name,
+                    from_user_id: from_user_id.clone(),
+                    to_user_id: to_user_id.clone(),
+                    all_channels: false,
+                    response_sender,
+                })
+            },
+            merge.response_sender,
+        );
+    }
+
+    // Saves and stops every channel
+    pub fn quit(&self) {
+        let start = Utc::now();
+        self.broadcast(Command::Quit);
+        let end = Utc::now();
+        info!(
+            "Quitting {} channels took {}",
+            self.channels.len(),
+            end - start
+        );
+    }
+
+    // Makes sure the channel has a database and a running listener, creating them if this is the
+    // first time the channel connects
+    pub fn connect(&mut self, channel_name: &str) -> Result<(), MyError> {
+        if self.channels.contains_key(channel_name) {
+            return Ok(());
+        }
+
+        if self.refused_channels.contains(channel_name) {
+            warn!(
+                "Refusing to serve channel {}, its database could not be loaded",
+                channel_name
+            );
+            return Err(MyError::UnknownChannel(channel_name.to_string()));
+        }
+
+        let path = Path::new(&self.path).join(channel_name);
+        let path_str = match path.to_str() {
+            None => {
+                return Err(MyError::ChannelNameError(channel_name.to_string()));
+            }
+            Some(p) => p,
+        };
+
+        // Save the empty database right away, so the channel is loaded on the next start even if
+        // no save happens before then
+        let c = match ChannelPoints::load(path_str) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                self.refuse_channel(channel_name.to_string(), e);
+                return Err(MyError::UnknownChannel(channel_name.to_string()));
+            }
+            Err(e) => {
+                error!(
+                    "Error creating database for channel {}: {}",
+                    channel_name, e
+                );
+                return Err(MyError::InternalError(e.to_string()));
+            }
+            Ok(c) => c,
+        };
+        if let Err(e) = c.save() {
+            error!(
+                "Error creating database for channel {}: {}",
+                channel_name, e
+            );
+            return Err(MyError::InternalError(e.to_string()));
+        }
+
+        info!("Created database for channel {}", channel_name);
+
+        let (sender, receiver) = channel();
+        thread::spawn(move || listen_on_channel(c, receiver));
+        self.channels.insert(channel_name.to_string(), sender);
+
+        return Ok(());
+    }
+
+    pub fn forward(&self, channel_name: String, command: Command) {
         match self.channels.get(&channel_name) {
             None => {
-                println!("No sender available");
+                warn!("No sender available for channel {}", channel_name);
+                command.reject(MyError::UnknownChannel(channel_name));
             }
             Some(sender) => {
-                println!("Found a sender in points.channels");
-                sender.send(command).unwrap();
-                println!("Sent!");
+                if let Err(e) = sender.send(command) {
+                    error!("Channel {} is no longer listening", channel_name);
+                    e.0.reject(MyError::InternalError(format!(
+                        "channel {} is not running",
+                        channel_name
+                    )));
+                }
             }
         }
     }
 }
 
+// Moves a database that can't be read out of the way, and returns its new path
+fn quarantine(path: &str) -> io::Result<String> {
+    let quarantine_path = timestamped_path(path, QUARANTINE_SUFFIX);
+    fs::rename(path, &quarantine_path)?;
+    sync_parent_directory(path)?;
+
+    return Ok(quarantine_path);
+}
+
+// Writes the buffer to a temporary file first and moves it into place once it's safely on disk,
+// so a crash leaves either the old or the new file behind
+fn write_atomically(path: &str, buf: &[u8]) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, path)?;
+    sync_parent_directory(path)?;
+
+    return Ok(());
+}
+
+// Seasons are only rewritten when users are deleted from them, so the file is made read-only to
+// keep anything else from changing it
+fn write_season(path: &str, season: &Season) -> io::Result<()> {
+    let buf =
+        format::encode_season(season).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_atomically(path, &buf)?;
+
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+
+    return fs::set_permissions(path, permissions);
+}
+
+fn parent_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if p != Path::new("") => return p,
+        _ => return Path::new("."),
+    }
+}
+
+// Makes the rename of a database file durable
+#[cfg(unix)]
+fn sync_parent_directory(path: &str) -> io::Result<()> {
+    return File::open(parent_directory(Path::new(path)))?.sync_all();
+}
+
+#[cfg(not(unix))]
+fn sync_parent_directory(_path: &str) -> io::Result<()> {
+    return Ok(());
+}
+
+// Up to `limit` entries of the index in rank order, starting at the given zero-based position
+fn leaderboard(ranks: &RankIndex, offset: usize, limit: usize) -> Vec<LeaderboardEntry> {
+    let mut entries: Vec<LeaderboardEntry> = Vec::with_capacity(limit.min(ranks.len()));
+
+    for (i, (points, user_id)) in ranks.iter_from(offset).take(limit).enumerate() {
+        let rank = match entries.last() {
+            None => ranks.count_above(points) as u64 + 1,
+            // Users with the same amount of points share a rank
+            Some(previous) if previous.points == points => previous.rank,
+            Some(_) => (offset + i) as u64 + 1,
+        };
+
+        entries.push(LeaderboardEntry {
+            user_id: user_id.to_string(),
+            points,
+            rank,
+        });
+    }
+
+    return entries;
+}
+
+fn unknown_channel(channel_name: &str) -> ChannelFailure {
+    return ChannelFailure {
+        channel_name: channel_name.to_string(),
+        error: MyError::UnknownChannel(channel_name.to_string()),
+    };
+}
+
+fn wait_for_all(receivers: Vec<Receiver<()>>) {
+    for receiver in receivers {
+        // An error means the channel stopped without acknowledging, nothing to wait for
+        let _ = receiver.recv();
+    }
+}
+
 fn listen_on_channel(c: ChannelPoints, receiver: Receiver<Command>) {
     c.listen(receiver);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_without_points_are_not_added() {
+        let mut c = ChannelPoints::new("");
+        c.add_points("rich".to_string(), 10);
+
+        assert_eq!(c.remove_points("ghost1".to_string(), 5), 0);
+        assert_eq!(
+            c.transfer_points("rich".to_string(), "ghost2".to_string(), 0)
+                .ok(),
+            Some(true)
+        );
+        c.bulk_edit(vec![("ghost3".to_string(), -5)]);
+        assert_eq!(c.set_points("ghost4".to_string(), 0), 0);
+
+        assert_eq!(c.ranks.len(), 1);
+        assert_eq!(c.user_id_to_points.len(), 1);
+        assert_eq!(c.pending_changes.len(), 1);
+    }
+
+    #[test]
+    fn transfer_overflow_moves_nothing() {
+        let mut c = ChannelPoints::new("");
+        c.add_points("a".to_string(), 10);
+        c.set_points("b".to_string(), u64::MAX - 5);
+
+        assert!(c
+            .transfer_points("a".to_string(), "b".to_string(), 10)
+            .is_err());
+        assert_eq!(
+            c.transfer_points("a".to_string(), "b".to_string(), 11).ok(),
+            Some(false)
+        );
+        assert_eq!(c.get_points("a"), 10);
+        assert_eq!(c.get_points("b"), u64::MAX - 5);
+    }
+
+    #[test]
+    fn users_keep_their_row_at_zero_points() {
+        let mut c = ChannelPoints::new("");
+        c.add_points("a".to_string(), 10);
+        c.remove_points("a".to_string(), 10);
+
+        assert_eq!(c.ranks.len(), 1);
+        assert_eq!(c.get_points("a"), 0);
+    }
+
+    fn close_season(c: &mut ChannelPoints, season_id: &str) -> Result<u64, MyError> {
+        let (sender, _) = channel();
+        return c.close_season(&CloseSeason {
+            channel_name: String::new(),
+            season_id: season_id.to_string(),
+            reset: false,
+            response_sender: ResponseSender::local(sender),
+        });
+    }
+
+    fn entries(ranks: &RankIndex) -> Vec<(String, u64)> {
+        return leaderboard(ranks, 0, ranks.len())
+            .into_iter()
+            .map(|entry| (entry.user_id, entry.points))
+            .collect();
+    }
+
+    #[test]
+    fn journal_replays_on_top_of_a_saved_database() {
+        let path = test_path("journal_replays_on_top_of_a_saved_database", "channel");
+
+        let mut c = ChannelPoints::load(&path).unwrap();
+        c.add_points("a".to_string(), 5);
+        assert!(c.write_journal().is_ok());
+        assert!(close_season(&mut c, "s1").is_ok());
+        assert!(c.write_journal().is_ok());
+        c.add_points("a".to_string(), 10);
+        assert!(c.write_journal().is_ok());
+        assert!(c.reset().is_ok());
+        assert!(c.write_journal().is_ok());
+        c.add_points("b".to_string(), 3);
+        assert!(c.write_journal().is_ok());
+
+        let check = |c: &ChannelPoints| {
+            assert_eq!(entries(&c.ranks), vec![("b".to_string(), 3)]);
+            assert_eq!(c.user_id_to_points.len(), 1);
+            assert_eq!(
+                entries(c.load_season("s1").ok().unwrap().ranks()),
+                vec![("a".to_string(), 5)]
+            );
+        };
+        check(&c);
+
+        // Crashed before any save, everything comes from the journal
+        drop(c);
+        let c = ChannelPoints::load(&path).unwrap();
+        check(&c);
+
+        // Crashed after the save, but before the journal was cleared. Replaying the journal
+        // again must not change anything
+        c.write_database(&path).unwrap();
+        drop(c);
+        let c = ChannelPoints::load(&path).unwrap();
+        check(&c);
+
+        // The journal is only cleared once the changes are saved
+        c.save().unwrap();
+        assert_eq!(fs::metadata(format!("{}.journal", path)).unwrap().len(), 0);
+        drop(c);
+        check(&ChannelPoints::load(&path).unwrap());
+    }
+
+    #[test]
+    fn old_databases_are_migrated() {
+        let path = test_path("old_databases_are_migrated", "channel");
+
+        // Version 1: the header, followed by the points in rank order
+        let mut v1 = b"PJPT".to_vec();
+        v1.extend_from_slice(&u32_to_buf(1));
+        v1.extend(bincode::serialize(&vec![(10u64, "a".to_string())]).unwrap());
+        fs::write(&path, &v1).unwrap();
+
+        let c = ChannelPoints::load(&path).unwrap();
+        assert_eq!(c.get_points("a"), 10);
+
+        // The old file is kept, and the database is saved in the current version right away
+        assert_eq!(fs::read(format!("{}.v1", path)).unwrap(), v1);
+        let saved = fs::read(&path).unwrap();
+        assert_eq!(buf_to_u32_unsafe(&saved[4..8]), format::CURRENT_VERSION);
+        drop(c);
+
+        assert_eq!(ChannelPoints::load(&path).unwrap().get_points("a"), 10);
+    }
+
+    fn file_names(directory: &str) -> Vec<String> {
+        let mut file_names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        file_names.sort();
+
+        return file_names;
+    }
+
+    #[test]
+    fn corrupt_databases_are_quarantined_and_refused() {
+        let path = test_path("corrupt_databases_are_quarantined_and_refused", "broken");
+        let directory = Path::new(&path).parent().unwrap().to_str().unwrap();
+        ChannelPoints::load(Path::new(directory).join("fine").to_str().unwrap())
+            .unwrap()
+            .save()
+            .unwrap();
+        fs::write(&path, b"PJPT\0\0\0\x02garbage").unwrap();
+
+        let mut p = Points::load_channels(directory).unwrap();
+        assert!(p.channels.contains_key("fine"));
+        assert!(p.refused_channels.contains("broken"));
+        assert!(p.connect("broken").is_err());
+        assert!(!Path::new(&path).exists());
+
+        let quarantined: Vec<String> = file_names(directory)
+            .into_iter()
+            .filter(|f| f.starts_with("broken.corrupt-"))
+            .collect();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(
+            fs::read(Path::new(directory).join(&quarantined[0])).unwrap(),
+            b"PJPT\0\0\0\x02garbage"
+        );
+        p.quit();
+
+        // Still refused on the next start, the quarantined copy is left alone
+        let mut p = Points::load_channels(directory).unwrap();
+        assert!(p.refused_channels.contains("broken"));
+        assert!(p.connect("broken").is_err());
+        p.quit();
+
+        // A database that is corrupt again in the same second doesn't replace the first copy
+        fs::write(&path, b"PJPT\0\0\0\x02garbage").unwrap();
+        let p = Points::load_channels(directory).unwrap();
+        p.quit();
+        let quarantined = file_names(directory)
+            .into_iter()
+            .filter(|f| f.starts_with("broken.corrupt-"))
+            .count();
+        assert_eq!(quarantined, 2);
+    }
+
+    #[test]
+    fn unknown_versions_are_refused_in_place() {
+        let path = test_path("unknown_versions_are_refused_in_place", "newer");
+        let directory = Path::new(&path).parent().unwrap().to_str().unwrap();
+        let mut newer = b"PJPT".to_vec();
+        newer.extend_from_slice(&u32_to_buf(format::CURRENT_VERSION + 1));
+        fs::write(&path, &newer).unwrap();
+
+        let mut p = Points::load_channels(directory).unwrap();
+        assert!(p.refused_channels.contains("newer"));
+        assert!(p.connect("newer").is_err());
+
+        // Left for a newer server to read
+        assert_eq!(file_names(directory), vec!["newer".to_string()]);
+        assert_eq!(fs::read(&path).unwrap(), newer);
+    }
+
+    #[test]
+    fn closed_seasons_are_stored_in_files() {
+        let path = test_path("closed_seasons_are_stored_in_files", "channel");
+
+        let mut c = ChannelPoints::load(&path).unwrap();
+        c.add_points("a".to_string(), 10);
+        c.add_points("b".to_string(), 5);
+        assert_eq!(close_season(&mut c, "s1").ok(), Some(2));
+        c.add_points("a".to_string(), 1);
+
+        let season_path = format!("{}.season-s1", path);
+        assert!(fs::metadata(&season_path).unwrap().permissions().readonly());
+        assert!(close_season(&mut c, "s1").is_err());
+
+        // Seasons are not part of the database
+        c.save().unwrap();
+        drop(c);
+        let c = ChannelPoints::load(&path).unwrap();
+        assert_eq!(c.season_ids().ok(), Some(vec!["s1".to_string()]));
+
+        let top = c.season_leaderboard("s1", 10).ok().unwrap();
+        assert_eq!(
+            top.into_iter()
+                .map(|entry| (entry.user_id, entry.points, entry.rank))
+                .collect::<Vec<_>>(),
+            vec![("a".to_string(), 10, 1), ("b".to_string(), 5, 2)]
+        );
+        let b = c.season_rank("s1", "b".to_string()).ok().unwrap().unwrap();
+        assert_eq!((b.points, b.rank), (5, 2));
+        assert!(c.season_rank("s1", "c".to_string()).ok().unwrap().is_none());
+        assert!(c.season_rank("s2", "a".to_string()).is_err());
+    }
+
+    #[test]
+    fn deleted_users_are_deleted_from_seasons() {
+        let path = test_path("deleted_users_are_deleted_from_seasons", "channel");
+
+        let mut c = ChannelPoints::load(&path).unwrap();
+        c.add_points("a".to_string(), 10);
+        assert!(close_season(&mut c, "s1").is_ok());
+        c.add_points("b".to_string(), 5);
+        assert!(close_season(&mut c, "s2").is_ok());
+
+        let in_seasons = c
+            .delete_from_seasons(&["b".to_string(), "c".to_string()])
+            .ok()
+            .unwrap();
+        assert_eq!(in_seasons.into_iter().collect::<Vec<_>>(), vec!["b"]);
+
+        let s2 = c.load_season("s2").ok().unwrap();
+        assert_eq!(entries(s2.ranks()), vec![("a".to_string(), 10)]);
+        assert!(fs::metadata(format!("{}.season-s2", path))
+            .unwrap()
+            .permissions()
+            .readonly());
+    }
+
+    #[test]
+    fn version_2_seasons_are_moved_to_files() {
+        let path = test_path("version_2_seasons_are_moved_to_files", "channel");
+
+        // Version 2: the header, followed by the points in rank order and the closed seasons
+        let mut seasons = BTreeMap::new();
+        seasons.insert("s1".to_string(), vec![(3u64, "a".to_string())]);
+        let mut v2 = b"PJPT".to_vec();
+        v2.extend_from_slice(&u32_to_buf(2));
+        v2.extend(bincode::serialize(&(vec![(10u64, "a".to_string())], seasons)).unwrap());
+        fs::write(&path, &v2).unwrap();
+
+        // Journals of that time still hold the seasons that were closed since the last save
+        let (journal, _) = Journal::open(&format!("{}.journal", path)).unwrap();
+        journal
+            .append(&[Change::CloseSeason("s2".to_string())])
+            .unwrap();
+        drop(journal);
+
+        let c = ChannelPoints::load(&path).unwrap();
+        assert_eq!(fs::read(format!("{}.v2", path)).unwrap(), v2);
+        let mut season_ids = c.season_ids().ok().unwrap();
+        season_ids.sort();
+        assert_eq!(season_ids, vec!["s1".to_string(), "s2".to_string()]);
+
+        let s1 = c.load_season("s1").ok().unwrap();
+        assert_eq!(entries(s1.ranks()), vec![("a".to_string(), 3)]);
+        let s2 = c.load_season("s2").ok().unwrap();
+        assert_eq!(entries(s2.ranks()), vec![("a".to_string(), 10)]);
+    }
+}
@@ -0,0 +1,442 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+
+// Order of two entries in the index. Entries with more points come first, ties are broken by
+// user ID so the order is stable between runs
+fn compare(a_points: u64, a_user_id: &str, b_points: u64, b_user_id: &str) -> Ordering {
+    return b_points
+        .cmp(&a_points)
+        .then_with(|| a_user_id.cmp(b_user_id));
+}
+
+#[derive(Clone)]
+struct Node {
+    points: u64,
+    user_id: String,
+
+    // Heap priority of the treap, randomized to keep the tree balanced
+    priority: u64,
+
+    // Number of entries in the subtree rooted at this node
+    size: usize,
+
+    left: Option<Box<Node>>,
+    right: Option<Box<Node>>,
+}
+
+impl Node {
+    fn new(points: u64, user_id: String, priority: u64) -> Node {
+        return Node {
+            points,
+            user_id,
+            priority,
+            size: 1,
+            left: None,
+            right: None,
+        };
+    }
+
+    fn update_size(&mut self) {
+        self.size = 1 + size(&self.left) + size(&self.right);
+    }
+}
+
+fn size(node: &Option<Box<Node>>) -> usize {
+    match node {
+        None => 0,
+        Some(n) => n.size,
+    }
+}
+
+// Splits the tree into entries ordered before the given key, and entries ordered at or after it
+fn split(
+    node: Option<Box<Node>>,
+    points: u64,
+    user_id: &str,
+) -> (Option<Box<Node>>, Option<Box<Node>>) {
+    match node {
+        None => return (None, None),
+        Some(mut n) => {
+            if compare(n.points, &n.user_id, points, user_id) == Ordering::Less {
+                let (left, right) = split(n.right.take(), points, user_id);
+                n.right = left;
+                n.update_size();
+                return (Some(n), right);
+            } else {
+                let (left, right) = split(n.left.take(), points, user_id);
+                n.left = right;
+                n.update_size();
+                return (left, Some(n));
+            }
+        }
+    }
+}
+
+// Joins two trees where every entry in `a` is ordered before every entry in `b`
+fn merge(a: Option<Box<Node>>, b: Option<Box<Node>>) -> Option<Box<Node>> {
+    match (a, b) {
+        (None, b) => return b,
+        (a, None) => return a,
+        (Some(mut a), Some(mut b)) => {
+            if a.priority > b.priority {
+                a.right = merge(a.right.take(), Some(b));
+                a.update_size();
+                return Some(a);
+            } else {
+                b.left = merge(Some(a), b.left.take());
+                b.update_size();
+                return Some(b);
+            }
+        }
+    }
+}
+
+fn remove(node: &mut Option<Box<Node>>, points: u64, user_id: &str) -> bool {
+    let removed = match node {
+        None => return false,
+        Some(n) => match compare(points, user_id, n.points, &n.user_id) {
+            Ordering::Less => remove(&mut n.left, points, user_id),
+            Ordering::Greater => remove(&mut n.right, points, user_id),
+            Ordering::Equal => {
+                let left = n.left.take();
+                let right = n.right.take();
+                *node = merge(left, right);
+                return true;
+            }
+        },
+    };
+
+    if removed {
+        if let Some(n) = node {
+            n.update_size();
+        }
+    }
+
+    return removed;
+}
+
+// Rank index of a channel.
+// Keeps (points, User ID) entries sorted by points, highest first, and answers positional
+// queries in logarithmic time
+#[derive(Clone)]
+pub struct RankIndex {
+    root: Option<Box<Node>>,
+
+    // State of the xorshift generator used for node priorities
+    seed: u64,
+}
+
+impl RankIndex {
+    pub fn new() -> RankIndex {
+        return RankIndex {
+            root: None,
+            seed: 0x2545_f491_4f6c_dd1d,
+        };
+    }
+
+    fn next_priority(&mut self) -> u64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        return self.seed;
+    }
+
+    pub fn len(&self) -> usize {
+        return size(&self.root);
+    }
+
+    pub fn insert(&mut self, points: u64, user_id: String) {
+        let priority = self.next_priority();
+        let (left, right) = split(self.root.take(), points, &user_id);
+        let node = Some(Box::new(Node::new(points, user_id, priority)));
+        self.root = merge(merge(left, node), right);
+    }
+
+    // Returns false if the entry was not in the index
+    pub fn remove(&mut self, points: u64, user_id: &str) -> bool {
+        return remove(&mut self.root, points, user_id);
+    }
+
+    // Number of entries ordered before the given key, i.e. the zero-based position of the key
+    pub fn count_before(&self, points: u64, user_id: &str) -> usize {
+        let mut count = 0;
+        let mut node = &self.root;
+
+        while let Some(n) = node {
+            if compare(n.points, &n.user_id, points, user_id) == Ordering::Less {
+                count += size(&n.left) + 1;
+                node = &n.right;
+            } else {
+                node = &n.left;
+            }
+        }
+
+        return count;
+    }
+
+    // Number of entries with strictly more points than the given value
+    pub fn count_above(&self, points: u64) -> usize {
+        // The empty string is ordered before every other User ID
+        return self.count_before(points, "");
+    }
+
+    // Iterates over the entries in rank order, starting at the given zero-based position
+    pub fn iter_from(&self, offset: usize) -> Iter<'_> {
+        let mut stack = Vec::new();
+        let mut node = &self.root;
+        let mut offset = offset;
+
+        while let Some(n) = node {
+            let left_size = size(&n.left);
+            if offset < left_size {
+                stack.push(&**n);
+                node = &n.left;
+            } else if offset == left_size {
+                stack.push(&**n);
+                break;
+            } else {
+                offset -= left_size + 1;
+                node = &n.right;
+            }
+        }
+
+        return Iter { stack };
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        return self.iter_from(0);
+    }
+}
+
+impl Default for RankIndex {
+    fn default() -> RankIndex {
+        return RankIndex::new();
+    }
+}
+
+impl fmt::Debug for RankIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RankIndex {{ len: {} }}", self.len())
+    }
+}
+
+pub struct Iter<'a> {
+    // Nodes whose own entry and right subtree are still to be visited
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (u64, &'a str);
+
+    fn next(&mut self) -> Option<(u64, &'a str)> {
+        let n = self.stack.pop()?;
+
+        let mut node = &n.right;
+        while let Some(child) = node {
+            self.stack.push(&**child);
+            node = &child.left;
+        }
+
+        return Some((n.points, &n.user_id));
+    }
+}
+
+// The index is stored on disk as the sorted vector of (points, User ID) entries
+impl Serialize for RankIndex {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for entry in self.iter() {
+            seq.serialize_element(&entry)?;
+        }
+        return seq.end();
+    }
+}
+
+impl<'de> Deserialize<'de> for RankIndex {
+    fn deserialize<D>(deserializer: D) -> Result<RankIndex, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let entries: Vec<(u64, String)> = Vec::deserialize(deserializer)?;
+
+        let mut index = RankIndex::new();
+        for (points, user_id) in entries {
+            index.insert(points, user_id);
+        }
+
+        return Ok(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bincode::{deserialize, serialize};
+
+    fn entries(index: &RankIndex) -> Vec<(u64, String)> {
+        return index
+            .iter()
+            .map(|(points, user_id)| (points, user_id.to_string()))
+            .collect();
+    }
+
+    fn index_of(entries: &[(u64, &str)]) -> RankIndex {
+        let mut index = RankIndex::new();
+        for (points, user_id) in entries {
+            index.insert(*points, user_id.to_string());
+        }
+
+        return index;
+    }
+
+    #[test]
+    fn insert_keeps_rank_order() {
+        let index = index_of(&[(5, "b"), (10, "a"), (1, "c")]);
+
+        assert_eq!(index.len(), 3);
+        assert_eq!(
+            entries(&index),
+            vec![
+                (10, "a".to_string()),
+                (5, "b".to_string()),
+                (1, "c".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_are_ordered_by_user_id() {
+        let index = index_of(&[(5, "c"), (5, "a"), (7, "z"), (5, "b")]);
+
+        assert_eq!(
+            entries(&index),
+            vec![
+                (7, "z".to_string()),
+                (5, "a".to_string()),
+                (5, "b".to_string()),
+                (5, "c".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let mut index = index_of(&[(5, "a"), (5, "b"), (3, "c")]);
+
+        assert!(index.remove(5, "a"));
+        assert_eq!(index.len(), 2);
+        assert_eq!(
+            entries(&index),
+            vec![(5, "b".to_string()), (3, "c".to_string())]
+        );
+
+        // The points must match too
+        assert!(!index.remove(4, "c"));
+        assert!(!index.remove(5, "a"));
+        assert_eq!(index.len(), 2);
+
+        assert!(index.remove(3, "c"));
+        assert!(index.remove(5, "b"));
+        assert_eq!(index.len(), 0);
+        assert!(!index.remove(5, "b"));
+    }
+
+    #[test]
+    fn count_before_and_above() {
+        let index = index_of(&[(10, "a"), (5, "b"), (5, "c"), (1, "d")]);
+
+        assert_eq!(index.count_before(10, "a"), 0);
+        assert_eq!(index.count_before(5, "b"), 1);
+        assert_eq!(index.count_before(5, "c"), 2);
+        assert_eq!(index.count_before(1, "d"), 3);
+        // Keys that are not in the index count where they would be
+        assert_eq!(index.count_before(5, "bb"), 2);
+        assert_eq!(index.count_before(0, "a"), 4);
+        assert_eq!(index.count_before(11, "z"), 0);
+
+        assert_eq!(index.count_above(11), 0);
+        assert_eq!(index.count_above(10), 0);
+        assert_eq!(index.count_above(5), 1);
+        assert_eq!(index.count_above(4), 3);
+        assert_eq!(index.count_above(0), 4);
+    }
+
+    #[test]
+    fn iter_from() {
+        let index = index_of(&[(4, "a"), (3, "b"), (2, "c"), (1, "d")]);
+
+        let from_two: Vec<u64> = index.iter_from(2).map(|(points, _)| points).collect();
+        assert_eq!(from_two, vec![2, 1]);
+
+        let from_last: Vec<u64> = index.iter_from(3).map(|(points, _)| points).collect();
+        assert_eq!(from_last, vec![1]);
+
+        assert_eq!(index.iter_from(4).count(), 0);
+        assert_eq!(index.iter_from(100).count(), 0);
+        assert_eq!(RankIndex::new().iter_from(0).count(), 0);
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let index = index_of(&[(10, "a"), (5, "b"), (5, "c")]);
+
+        let buf = serialize(&index).unwrap();
+        let decoded: RankIndex = deserialize(&buf).unwrap();
+
+        assert_eq!(entries(&decoded), entries(&index));
+    }
+
+    // Compares the index against a sorted vector over many random inserts and removes
+    #[test]
+    fn matches_sorted_vector() {
+        let mut index = RankIndex::new();
+        let mut model: Vec<(u64, String)> = Vec::new();
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut random = move |n: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            return seed % n;
+        };
+
+        for _ in 0..2000 {
+            let user_id = format!("user{}", random(200));
+            let existing = model.iter().position(|(_, u)| *u == user_id);
+
+            match existing {
+                Some(i) if random(2) == 0 => {
+                    let (points, user_id) = model.remove(i);
+                    assert!(index.remove(points, &user_id));
+                }
+                Some(_) => {}
+                None => {
+                    let points = random(20);
+                    index.insert(points, user_id.clone());
+                    model.push((points, user_id));
+                }
+            }
+
+            model.sort_by(|a, b| compare(a.0, &a.1, b.0, &b.1));
+
+            assert_eq!(index.len(), model.len());
+            let position = random(model.len() as u64 + 2) as usize;
+            let expected: Vec<(u64, String)> = model.iter().skip(position).cloned().collect();
+            let actual: Vec<(u64, String)> = index
+                .iter_from(position)
+                .map(|(points, user_id)| (points, user_id.to_string()))
+                .collect();
+            assert_eq!(actual, expected);
+
+            let points = random(22);
+            let above = model.iter().filter(|(p, _)| *p > points).count();
+            assert_eq!(index.count_above(points), above);
+        }
+    }
+}
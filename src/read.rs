@@ -1,34 +1,67 @@
 use std::io;
 use std::io::Read;
-use std::net::TcpStream;
 
 use common::MyError;
 use utils::*;
 
-pub fn read_header(client: &mut TcpStream) -> Result<(u8, u32), MyError> {
-    let mut header_buffer = [0; 5];
+pub const HEADER_SIZE: usize = 9;
 
-    loop {
-        match client.read_exact(&mut header_buffer) {
-            // Retry
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+// Bytes read from a socket at a time
+const READ_CHUNK_SIZE: usize = 4096;
 
-            Err(e) => return Err(MyError::IoError(e)),
-            Ok(_) => return Ok((header_buffer[0], buf_to_u32_unsafe(&header_buffer[1..]))),
-        }
+pub struct Header {
+    pub command: u8,
+
+    // Chosen by the client, and sent back in the response to the command
+    pub request_id: u32,
+
+    pub body_size: u32,
+}
+
+// Returns the header at the start of the buffer, or None if the whole header has not arrived yet
+pub fn parse_header(buf: &[u8]) -> Option<Header> {
+    if buf.len() < HEADER_SIZE {
+        return None;
     }
+
+    return Some(Header {
+        command: buf[0],
+        request_id: buf_to_u32_unsafe(&buf[1..5]),
+        body_size: buf_to_u32_unsafe(&buf[5..9]),
+    });
+}
+
+pub enum ReadState {
+    // Nothing left to read for now
+    Drained,
+    // The buffer reached its limit, there may be more to read
+    Full,
+    // The other side has closed the connection
+    Closed,
 }
 
-pub fn read_body(client: &mut TcpStream, size: usize) -> Result<Vec<u8>, MyError> {
-    let mut body_buffer = vec![0; size];
+// Reads everything that can be read without blocking into the buffer, until the buffer holds at
+// least `limit` bytes
+pub fn read_available<R: Read>(
+    client: &mut R,
+    buf: &mut Vec<u8>,
+    limit: usize,
+) -> Result<ReadState, MyError> {
+    let mut chunk = [0; READ_CHUNK_SIZE];
 
-    loop {
-        match client.read_exact(&mut body_buffer) {
+    while buf.len() < limit {
+        match client.read(&mut chunk) {
             // Retry
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
 
+            // Nothing left to read for now
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(ReadState::Drained),
+
             Err(e) => return Err(MyError::IoError(e)),
-            Ok(_) => return Ok(body_buffer),
+            Ok(0) => return Ok(ReadState::Closed),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
         }
     }
+
+    return Ok(ReadState::Full);
 }
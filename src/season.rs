@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use ranks::RankIndex;
+
+// Final standings of a closed season of a channel. A season never changes once it's closed,
+// except that users who are deleted from the channel are deleted from its seasons too.
+// Every season is stored in a file of its own, and only loaded while it is being read
+#[derive(Debug)]
+pub struct Season {
+    // Points and User IDs at the end of the season, sorted by points
+    ranks: RankIndex,
+
+    // Key = User ID
+    // Value = Points at the end of the season
+    user_id_to_points: HashMap<String, u64>,
+}
+
+impl Season {
+    pub fn new(ranks: RankIndex) -> Season {
+        let user_id_to_points = ranks
+            .iter()
+            .map(|(points, user_id)| (user_id.to_string(), points))
+            .collect();
+
+        return Season {
+            ranks,
+            user_id_to_points,
+        };
+    }
+
+    pub fn ranks(&self) -> &RankIndex {
+        return &self.ranks;
+    }
+
+    // Returns false if the user was not in the season
+    pub fn remove(&mut self, user_id: &str) -> bool {
+        return match self.user_id_to_points.remove(user_id) {
+            None => false,
+            Some(points) => self.ranks.remove(points, user_id),
+        };
+    }
+
+    // Returns None if the user was not in the season
+    pub fn get_points(&self, user_id: &str) -> Option<u64> {
+        return self.user_id_to_points.get(user_id).cloned();
+    }
+}
+
+// Only the rank index is stored on disk, the user map is rebuilt from it
+impl Serialize for Season {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        return self.ranks.serialize(serializer);
+    }
+}
+
+impl<'de> Deserialize<'de> for Season {
+    fn deserialize<D>(deserializer: D) -> Result<Season, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let ranks = RankIndex::deserialize(deserializer)?;
+
+        return Ok(Season::new(ranks));
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::io;
+use std::net;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+
+use mio::event::Event;
+use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use client::{Client, Command, Response, ResponseQueue};
+use config::Config;
+
+const LISTENER: Token = Token(0);
+// Woken up when responses are waiting to be written
+const WAKER: Token = Token(1);
+// Clients get tokens starting from here
+const FIRST_CLIENT: usize = 2;
+
+const EVENTS_CAPACITY: usize = 1024;
+
+// Accepts connections and drives every client from a single thread
+pub struct Server {
+    poll: Poll,
+    listener: TcpListener,
+    waker: Arc<Waker>,
+
+    clients: HashMap<Token, Client>,
+    // Tokens are never reused, so responses to a client that is gone can't reach a new client
+    next_token: usize,
+
+    request_sender: Sender<Command>,
+    response_sender: Sender<(Token, Response)>,
+    response_receiver: Receiver<(Token, Response)>,
+
+    max_connections: usize,
+    max_body_size: u32,
+    // Clients must authenticate with this secret, if there is one
+    secret: Option<Arc<Vec<u8>>>,
+    max_leaderboard_size: u32,
+}
+
+impl Server {
+    pub fn new(config: &Config, request_sender: Sender<Command>) -> io::Result<Server> {
+        let listener = net::TcpListener::bind(&config.host)?;
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
+
+        let poll = Poll::new()?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+        let (response_sender, response_receiver) = channel();
+
+        return Ok(Server {
+            poll,
+            listener,
+            waker,
+            clients: HashMap::new(),
+            next_token: FIRST_CLIENT,
+            request_sender,
+            response_sender,
+            response_receiver,
+            max_connections: config.max_connections,
+            max_body_size: config.max_body_size,
+            secret: config.secret().map(|s| Arc::new(s.to_vec())),
+            max_leaderboard_size: config.max_leaderboard_size,
+        });
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.write_responses(),
+                    token => self.handle_event(token, event),
+                }
+            }
+        }
+    }
+
+    // Accepts every waiting connection
+    fn accept(&mut self) {
+        loop {
+            let (mut stream, address) = match self.listener.accept() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                    return;
+                }
+                Ok(s) => s,
+            };
+
+            if self.clients.len() >= self.max_connections {
+                warn!(
+                    "Refusing connection from {}, {} clients are already connected",
+                    address, self.max_connections
+                );
+                continue;
+            }
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            if let Err(e) = self.poll.registry().register(
+                &mut stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            ) {
+                error!("Error registering connection from {}: {}", address, e);
+                continue;
+            }
+
+            debug!("Accepted connection from {}", address);
+
+            let response_queue =
+                ResponseQueue::new(token, self.response_sender.clone(), self.waker.clone());
+            let client = Client::new(
+                stream,
+                self.request_sender.clone(),
+                response_queue,
+                self.max_body_size,
+                self.secret.clone(),
+                self.max_leaderboard_size,
+            );
+            self.clients.insert(token, client);
+        }
+    }
+
+    fn handle_event(&mut self, token: Token, event: &Event) {
+        let result = match self.clients.get_mut(&token) {
+            None => return,
+            Some(client) => {
+                let mut result = Ok(());
+                if event.is_readable() || event.is_read_closed() {
+                    result = client.on_readable();
+                }
+                if result.is_ok() && event.is_writable() {
+                    result = client.on_writable();
+                }
+                result
+            }
+        };
+
+        if let Err(e) = result {
+            // The stream is out of sync or gone, the client will have to reconnect
+            info!("An error occured in client: {}", e);
+            self.close(token);
+        } else if event.is_error() {
+            self.close(token);
+        } else {
+            self.close_if_done(token);
+        }
+    }
+
+    // Hands every waiting response to its client
+    fn write_responses(&mut self) {
+        while let Ok((token, response)) = self.response_receiver.try_recv() {
+            let result = match self.clients.get_mut(&token) {
+                // The client disconnected before the command was answered
+                None => continue,
+                Some(client) => client.on_response(response),
+            };
+
+            if let Err(e) = result {
+                info!("An error occured in client: {}", e);
+                self.close(token);
+            } else {
+                self.close_if_done(token);
+            }
+        }
+    }
+
+    fn close_if_done(&mut self, token: Token) {
+        let done = match self.clients.get(&token) {
+            None => false,
+            Some(client) => client.is_done(),
+        };
+
+        if done {
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut client) = self.clients.remove(&token) {
+            debug!("Closing connection {:?}", client.stream());
+            if let Err(e) = self.poll.registry().deregister(client.stream()) {
+                error!("Error deregistering connection: {}", e);
+            }
+        }
+    }
+}
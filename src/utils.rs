@@ -1,3 +1,7 @@
+use chrono::prelude::*;
+
+use std::path::Path;
+
 use common::MyError;
 
 /*
@@ -30,6 +34,16 @@ pub fn u64_to_buf(value: u64) -> [u8; 8] {
     return buffer;
 }
 
+pub fn u32_to_buf(value: u32) -> [u8; 4] {
+    let mut buffer = [0; 4];
+    buffer[0] = ((value >> 24) & 0xFF) as u8;
+    buffer[1] = ((value >> 16) & 0xFF) as u8;
+    buffer[2] = ((value >> 8) & 0xFF) as u8;
+    buffer[3] = ((value) & 0xFF) as u8;
+
+    return buffer;
+}
+
 /*
 pub fn u8_to_buf(value: u8) -> [u8; 1] {
     let mut buffer = [0; 1];
@@ -62,3 +76,30 @@ pub fn buf_to_u64(buffer: &[u8]) -> Result<u64, MyError> {
 
     return Ok(result);
 }
+
+// Path next to the given one, made of the path, the suffix and the current time. Never the path
+// of an existing file, even one made in the same second
+pub fn timestamped_path(path: &str, suffix: &str) -> String {
+    let base_path = format!("{}{}{}", path, suffix, Utc::now().format("%Y%m%d%H%M%S"));
+
+    let mut new_path = base_path.clone();
+    let mut n = 1;
+    while Path::new(&new_path).exists() {
+        new_path = format!("{}-{}", base_path, n);
+        n += 1;
+    }
+
+    return new_path;
+}
+
+// Path of a file in a directory of its own, emptied for the test with the given name
+#[cfg(test)]
+pub fn test_path(test_name: &str, file_name: &str) -> String {
+    use std::{env, fs, process};
+
+    let directory = env::temp_dir().join(format!("pajbot2-points-{}-{}", process::id(), test_name));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+
+    return directory.join(file_name).to_str().unwrap().to_string();
+}